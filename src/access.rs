@@ -0,0 +1,181 @@
+use super::*;
+use axum::http::{header, Request};
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+//結果ページのリンクを締め切りから何日間有効にするか
+const RESULT_LINK_DAYS: i64 = 30;
+//出欠エクスポートのリンクの有効時間
+const EXPORT_LINK_MINUTES: i64 = 60;
+//個人ページのリンクの有効時間
+const MEMBER_LINK_HOURS: i64 = 24;
+//管理画面にログインしている時間
+const ADMIN_SESSION_HOURS: i64 = 12;
+pub const ADMIN_SESSION_COOKIE: &str = "admin_session";
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn secret() -> &'static str {
+    SETTINGS
+        .RESULT_LINK_SECRET
+        .as_deref()
+        .unwrap_or(&SETTINGS.TOKEN)
+}
+
+fn mac(secret: &str, attendance_id: &str, expires: i64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(format!("{attendance_id}:{expires}").as_bytes());
+    mac
+}
+
+fn sign_with(secret: &str, attendance_id: &str, expires: DateTime<Utc>) -> String {
+    let expires = expires.timestamp();
+    let signature = mac(secret, attendance_id, expires).finalize().into_bytes();
+    format!("{expires}.{}", hex::encode(signature))
+}
+
+fn verify_with(secret: &str, attendance_id: &str, token: &str, now: DateTime<Utc>) -> bool {
+    let Some((expires, signature)) = token.split_once('.') else {return false};
+    let (Ok(expires), Ok(signature)) = (expires.parse::<i64>(), hex::decode(signature)) else {return false};
+    if expires < now.timestamp() {
+        return false;
+    }
+    mac(secret, attendance_id, expires)
+        .verify_slice(&signature)
+        .is_ok()
+}
+
+/// 出欠カードの結果リンクに付ける署名付きのトークン。締め切りからしばらくすると使えなくなる
+pub fn sign_result_token(attendance_id: &str, finishing_time: DateTime<Utc>) -> String {
+    sign_with(
+        secret(),
+        attendance_id,
+        finishing_time + Duration::days(RESULT_LINK_DAYS),
+    )
+}
+
+pub fn verify_result_token(attendance_id: &str, token: &str) -> bool {
+    verify_with(secret(), attendance_id, token, Utc::now())
+}
+
+//期間ごとに別の署名になるので、リンクの期間を書き換えても使えない
+fn export_subject(from: Option<NaiveDate>, to: Option<NaiveDate>) -> String {
+    let date = |date: Option<NaiveDate>| date.map(|d| d.to_string()).unwrap_or_default();
+    format!("export:{}:{}", date(from), date(to))
+}
+
+/// 管理者キーを渡さずに、その期間のCSVだけをしばらくダウンロードできるトークン
+pub fn sign_export_token(from: Option<NaiveDate>, to: Option<NaiveDate>, now: DateTime<Utc>) -> String {
+    sign_with(
+        secret(),
+        &export_subject(from, to),
+        now + Duration::minutes(EXPORT_LINK_MINUTES),
+    )
+}
+
+pub fn verify_export_token(from: Option<NaiveDate>, to: Option<NaiveDate>, token: &str) -> bool {
+    verify_with(secret(), &export_subject(from, to), token, Utc::now())
+}
+
+fn member_subject(user_id: &str) -> String {
+    format!("member:{user_id}")
+}
+
+/// 結果ページから個人ページに飛ぶためのトークン
+pub fn sign_member_token(user_id: &str, now: DateTime<Utc>) -> String {
+    sign_with(
+        secret(),
+        &member_subject(user_id),
+        now + Duration::hours(MEMBER_LINK_HOURS),
+    )
+}
+
+pub fn verify_member_token(user_id: &str, token: &str) -> bool {
+    verify_with(secret(), &member_subject(user_id), token, Utc::now())
+}
+
+//管理者キーで署名するので、キーを変えれば全てのログインが無効になる
+fn session_secret() -> Option<&'static str> {
+    SETTINGS.ADMIN_KEY.as_deref().filter(|key| !key.is_empty())
+}
+
+/// 管理画面のログイン用のCookieの値
+pub fn sign_admin_session(now: DateTime<Utc>) -> Option<String> {
+    Some(sign_with(
+        session_secret()?,
+        ADMIN_SESSION_COOKIE,
+        now + Duration::hours(ADMIN_SESSION_HOURS),
+    ))
+}
+
+/// ブラウザとの間がHTTPSのときだけSecureを付ける (HTTPだとブラウザが送ってくれない)
+pub fn admin_session_cookie(token: &str, https: bool) -> String {
+    let secure = if https { "; Secure" } else { "" };
+    format!(
+        "{ADMIN_SESSION_COOKIE}={token}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict{secure}",
+        ADMIN_SESSION_HOURS * 60 * 60
+    )
+}
+
+fn cookie<'a, B>(request: &'a Request<B>, name: &str) -> Option<&'a str> {
+    request
+        .headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|pair| pair.trim().strip_prefix(name)?.strip_prefix('='))
+}
+
+/// 管理者キーをAuthorization: Bearerで渡すか、管理画面にログインしていればtrue。
+/// URLに載せると履歴やアクセスログに残るので、クエリでは受け付けない
+pub fn is_admin_request<B>(request: &Request<B>) -> bool {
+    let from_header = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let from_cookie = cookie(request, ADMIN_SESSION_COOKIE).is_some_and(|token| {
+        session_secret()
+            .is_some_and(|secret| verify_with(secret, ADMIN_SESSION_COOKIE, token, Utc::now()))
+    });
+    is_admin(from_header) || from_cookie
+}
+
+/// APIは管理者だけが使える
+pub async fn require_admin<B>(request: Request<B>, next: Next<B>) -> axum::response::Response {
+    if is_admin_request(&request) {
+        next.run(request).await
+    } else {
+        StatusCode::UNAUTHORIZED.into_response()
+    }
+}
+
+/// 管理画面はログインしていなければログインページに送る
+pub async fn require_admin_page<B>(request: Request<B>, next: Next<B>) -> axum::response::Response {
+    if is_admin_request(&request) {
+        next.run(request).await
+    } else {
+        axum::response::Redirect::to("/admin/login").into_response()
+    }
+}
+
+#[test]
+fn result_token_test() {
+    let now = Utc::now();
+    let token = sign_with("secret", "attendance1", now + Duration::days(1));
+    assert!(verify_with("secret", "attendance1", &token, now));
+    assert!(!verify_with("secret", "attendance2", &token, now));
+    assert!(!verify_with("other", "attendance1", &token, now));
+    assert!(!verify_with("secret", "attendance1", &token, now + Duration::days(2)));
+    assert!(!verify_with("secret", "attendance1", "garbage", now));
+}
+
+#[test]
+fn export_subject_test() {
+    let date = |d: u32| NaiveDate::from_ymd_opt(2023, 2, d);
+    assert_eq!(export_subject(date(1), date(28)), "export:2023-02-01:2023-02-28");
+    assert_ne!(export_subject(date(1), date(28)), export_subject(date(1), None));
+}
@@ -0,0 +1,497 @@
+use super::*;
+use axum::extract::{ConnectInfo, Form};
+use axum::http::{header, HeaderMap};
+use axum::response::{IntoResponse, Redirect};
+use chrono::Weekday;
+use serde::{Deserialize, Serialize};
+
+//管理画面に表示する出欠の数
+const ADMIN_ATTENDANCE_COUNT: i64 = 20;
+
+pub fn router() -> Router {
+    let protected = Router::new()
+        .route("/admin", routing::get(dashboard))
+        .route("/admin/schedules", routing::post(create_schedule))
+        .route("/admin/schedules/:index", routing::post(update_schedule))
+        .route("/admin/schedules/:index/delete", routing::post(delete_schedule))
+        .route("/admin/schedules/:index/exceptions", routing::post(create_exception))
+        .route(
+            "/admin/schedules/:index/exceptions/:exception/delete",
+            routing::post(delete_exception),
+        )
+        .route("/admin/attendances/:id/close", routing::post(close_attendance))
+        .route("/admin/poll", routing::post(trigger_poll))
+        .layer(middleware::from_fn(require_admin_page));
+    Router::new()
+        .route("/admin/login", routing::get(login_page).post(login))
+        .route("/admin/logout", routing::post(logout))
+        .merge(protected)
+}
+
+/// 操作に失敗したときは理由を表示して、管理画面に戻れるようにする
+pub struct AdminError(StatusCode, String);
+impl From<Response> for AdminError {
+    fn from(response: Response) -> Self {
+        AdminError(StatusCode::BAD_REQUEST, response.get())
+    }
+}
+impl IntoResponse for AdminError {
+    fn into_response(self) -> axum::response::Response {
+        let AdminError(status, message) = self;
+        match render("admin_error.html", minijinja::context! { message }) {
+            Ok(html) => (status, Html(html)).into_response(),
+            Err(_) => status.into_response(),
+        }
+    }
+}
+
+type AdminResult<T> = std::result::Result<T, AdminError>;
+
+fn internal_error(e: impl std::fmt::Display) -> AdminError {
+    tracing::error!(error = %e, "admin operation failed");
+    AdminError(StatusCode::INTERNAL_SERVER_ERROR, "処理に失敗しました".to_string())
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    key: String,
+}
+
+async fn login_page() -> AdminResult<Html<String>> {
+    render("admin_login.html", minijinja::context! { failed => false })
+        .map(Html::from)
+        .map_err(internal_error)
+}
+
+async fn login(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Form(form): Form<LoginForm>,
+) -> axum::response::Response {
+    if is_admin(Some(&form.key)) {
+        if let Some(token) = sign_admin_session(Utc::now()) {
+            //プロキシの後ろではX-Forwarded-Protoで判断する
+            let https = request_scheme(&headers, peer) == "https";
+            return (
+                [(header::SET_COOKIE, admin_session_cookie(&token, https))],
+                Redirect::to("/admin"),
+            )
+                .into_response();
+        }
+    }
+    match render("admin_login.html", minijinja::context! { failed => true }) {
+        Ok(html) => (StatusCode::UNAUTHORIZED, Html(html)).into_response(),
+        Err(_) => StatusCode::UNAUTHORIZED.into_response(),
+    }
+}
+
+async fn logout() -> axum::response::Response {
+    (
+        [(
+            header::SET_COOKIE,
+            format!("{ADMIN_SESSION_COOKIE}=; Path=/; Max-Age=0; HttpOnly; SameSite=Strict"),
+        )],
+        Redirect::to("/admin/login"),
+    )
+        .into_response()
+}
+
+//フォームでは日本時間の"2023-02-20T10:00"の形で扱う
+fn to_local_input(datetime: &DateTime<Utc>) -> String {
+    datetime.with_timezone(&*TIMEZONE).format("%Y-%m-%dT%H:%M").to_string()
+}
+
+fn parse_local_input(text: &str) -> std::result::Result<DateTime<Utc>, Response> {
+    let local = NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M")
+        .map_err(|_| Response::DateParseError)?;
+    let local = local
+        .and_local_timezone(*TIMEZONE)
+        .single()
+        .ok_or(Response::UnvalidDate)?;
+    Ok(local.with_timezone(&Utc))
+}
+
+//空欄は指定なしとして扱う
+fn parse_optional<T: std::str::FromStr>(text: &Option<String>) -> std::result::Result<Option<T>, Response> {
+    match text.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(text) => text.parse().map(Some).map_err(|_| Response::NotEnoughArgment),
+    }
+}
+
+#[derive(Serialize)]
+pub struct ExceptionView {
+    pub index: usize,
+    pub datetime: String,
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ScheduleView {
+    pub index: usize,
+    pub id: String,
+    pub weekly: bool,
+    pub weekday: String,
+    pub weekday_jp: String,
+    pub time: String,
+    pub datetime: String,
+    //スケジュール自身の値 (空欄ならテンプレートの値を使う)
+    pub hour: Option<i64>,
+    //実際に使われる締め切りまでの時間
+    pub deadline_hours: i64,
+    pub capacity: Option<u32>,
+    pub template: String,
+    pub paused: bool,
+    pub exceptions: Vec<ExceptionView>,
+}
+
+/// 出欠確認を送るスケジュールだけを表示する。締め切りの集計などは出欠の一覧から操作する
+fn schedule_views(scheduler: &Scheduler) -> Vec<ScheduleView> {
+    let mut views = vec![];
+    for (index, schedule) in scheduler.schedules().iter().enumerate() {
+        let Todo::CreateAttendanceCheck { hour, capacity, ref template } = schedule.todo else {continue};
+        let mut view = ScheduleView {
+            index,
+            id: schedule.id.clone(),
+            weekly: false,
+            weekday: String::new(),
+            weekday_jp: String::new(),
+            time: String::new(),
+            datetime: String::new(),
+            hour,
+            deadline_hours: schedule.todo.deadline_hours().unwrap_or_default(),
+            capacity,
+            template: template.clone().unwrap_or_default(),
+            paused: schedule.paused,
+            exceptions: vec![],
+        };
+        match &schedule.schedule_type {
+            ScheduleType::OneTime { datetime } => view.datetime = to_local_input(datetime),
+            ScheduleType::Weekly {
+                weekday,
+                time,
+                exception,
+            } => {
+                view.weekly = true;
+                view.weekday = weekday.to_string();
+                view.weekday_jp = weekday_to_jp(*weekday);
+                view.time = time.format("%H:%M").to_string();
+                view.exceptions = exception
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(index, e)| {
+                        let ScheduleType::OneTime { datetime } = &e.schedule_type else {return None};
+                        let reason = match &e.todo {
+                            Todo::SendMessage { contents } => Some(contents.text.clone()),
+                            _ => None,
+                        };
+                        Some(ExceptionView {
+                            index,
+                            datetime: datetime.with_timezone(&*TIMEZONE).format("%Y/%m/%d").to_string(),
+                            reason,
+                        })
+                    })
+                    .collect();
+            }
+        }
+        views.push(view);
+    }
+    views
+}
+
+#[derive(Serialize)]
+pub struct AttendanceView {
+    pub summary: api::AttendanceSummary,
+    pub deadline: String,
+    pub open: bool,
+    pub token: String,
+}
+
+async fn dashboard() -> AdminResult<Html<String>> {
+    let schedules = schedule_views(&*SCHEDULER.get().unwrap().lock().await);
+    let now = Utc::now();
+    let attendances: Vec<AttendanceView> = api::fetch_summaries(None, None, ADMIN_ATTENDANCE_COUNT, 0)
+        .await
+        .map_err(internal_error)?
+        .into_iter()
+        .map(|summary| AttendanceView {
+            deadline: summary
+                .finishing_time
+                .with_timezone(&*TIMEZONE)
+                .format("%Y/%m/%d %H:%M")
+                .to_string(),
+            open: summary.finishing_time > now,
+            token: sign_result_token(&summary.attendance_id, summary.finishing_time),
+            summary,
+        })
+        .collect();
+    let weekdays: Vec<(String, String)> = [
+        Weekday::Mon,
+        Weekday::Tue,
+        Weekday::Wed,
+        Weekday::Thu,
+        Weekday::Fri,
+        Weekday::Sat,
+        Weekday::Sun,
+    ]
+    .iter()
+    .map(|weekday| (weekday.to_string(), weekday_to_jp(*weekday)))
+    .collect();
+    let mut templates: Vec<&String> = SETTINGS.EVENT_TEMPLATES.keys().collect();
+    templates.sort();
+    render(
+        "admin.html",
+        minijinja::context! { schedules, attendances, weekdays, templates },
+    )
+    .map(Html::from)
+    .map_err(internal_error)
+}
+
+#[derive(Deserialize)]
+pub struct ScheduleForm {
+    //編集中に一覧が変わっていないか確かめるための元の名前
+    original_id: Option<String>,
+    id: String,
+    kind: String,
+    weekday: Option<String>,
+    time: Option<String>,
+    datetime: Option<String>,
+    hour: Option<String>,
+    capacity: Option<String>,
+    template: Option<String>,
+    paused: Option<String>,
+}
+
+//空欄ならテンプレート無し。知らない名前は受け付けない
+fn template_from_form(template: &Option<String>) -> std::result::Result<Option<String>, Response> {
+    match template.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(name) if SETTINGS.EVENT_TEMPLATES.contains_key(name) => Ok(Some(name.to_string())),
+        Some(_) => Err(Response::EventNotFound),
+    }
+}
+
+fn schedule_from_form(form: &ScheduleForm) -> std::result::Result<Schedule, Response> {
+    if form.id.trim().is_empty() {
+        return Err(Response::NotEnoughArgment);
+    }
+    let hour: Option<i64> = parse_optional(&form.hour)?;
+    let capacity: Option<u32> = parse_optional(&form.capacity)?;
+    let template = template_from_form(&form.template)?;
+    let schedule_type = match form.kind.as_str() {
+        "weekly" => {
+            let weekday: Weekday = parse_optional(&form.weekday)?.ok_or(Response::NotEnoughArgment)?;
+            let time = form.time.as_deref().ok_or(Response::NotEnoughArgment)?;
+            let time = NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| Response::DateParseError)?;
+            ScheduleType::Weekly {
+                weekday,
+                time,
+                exception: vec![],
+            }
+        }
+        _ => {
+            let datetime = parse_local_input(form.datetime.as_deref().unwrap_or_default())?;
+            if datetime < Utc::now() {
+                return Err(Response::PassedDate);
+            }
+            ScheduleType::OneTime { datetime }
+        }
+    };
+    Ok(Schedule {
+        id: form.id.trim().to_string(),
+        schedule_type,
+        todo: Todo::CreateAttendanceCheck {
+            hour,
+            capacity,
+            template,
+        },
+        paused: form.paused.is_some(),
+    })
+}
+
+/// 一覧を表示してから変わっていれば、別のスケジュールを書き換えないように止める
+fn schedule_at<'a>(
+    scheduler: &'a mut Scheduler,
+    index: usize,
+    id: Option<&str>,
+) -> AdminResult<&'a mut Schedule> {
+    scheduler
+        .schedules_mut()
+        .get_mut(index)
+        .filter(|schedule| id.is_none_or(|id| schedule.id == id))
+        .ok_or_else(|| Response::EventNotFound.into())
+}
+
+async fn save(scheduler: &Scheduler) -> AdminResult<Redirect> {
+    scheduler
+        .save_shedule("schedule.json")
+        .await
+        .map_err(internal_error)?;
+    Ok(Redirect::to("/admin"))
+}
+
+async fn create_schedule(Form(form): Form<ScheduleForm>) -> AdminResult<Redirect> {
+    let schedule = schedule_from_form(&form)?;
+    let mut scheduler = SCHEDULER.get().unwrap().lock().await;
+    scheduler.push(schedule).await;
+    save(&scheduler).await
+}
+
+async fn update_schedule(
+    Path(index): Path<usize>,
+    Form(form): Form<ScheduleForm>,
+) -> AdminResult<Redirect> {
+    let mut updated = schedule_from_form(&form)?;
+    let mut scheduler = SCHEDULER.get().unwrap().lock().await;
+    let schedule = schedule_at(&mut scheduler, index, form.original_id.as_deref())?;
+    //毎週のままなら休みの登録は残す
+    if let (
+        ScheduleType::Weekly { exception, .. },
+        ScheduleType::Weekly {
+            exception: updated_exception,
+            ..
+        },
+    ) = (&mut schedule.schedule_type, &mut updated.schedule_type)
+    {
+        std::mem::swap(exception, updated_exception);
+    }
+    *schedule = updated;
+    save(&scheduler).await
+}
+
+#[derive(Deserialize)]
+pub struct TargetForm {
+    original_id: Option<String>,
+}
+
+async fn delete_schedule(
+    Path(index): Path<usize>,
+    Form(form): Form<TargetForm>,
+) -> AdminResult<Redirect> {
+    let mut scheduler = SCHEDULER.get().unwrap().lock().await;
+    schedule_at(&mut scheduler, index, form.original_id.as_deref())?;
+    scheduler.schedules_mut().remove(index);
+    save(&scheduler).await
+}
+
+#[derive(Deserialize)]
+pub struct ExceptionForm {
+    original_id: Option<String>,
+    date: String,
+    reason: Option<String>,
+}
+
+async fn create_exception(
+    Path(index): Path<usize>,
+    Form(form): Form<ExceptionForm>,
+) -> AdminResult<Redirect> {
+    let date = NaiveDate::parse_from_str(&form.date, "%Y-%m-%d").map_err(|_| Response::DateParseError)?;
+    let reason = form.reason.as_deref().map(str::trim).filter(|r| !r.is_empty());
+    let mut scheduler = SCHEDULER.get().unwrap().lock().await;
+    let schedule = schedule_at(&mut scheduler, index, form.original_id.as_deref())?;
+    add_exception(schedule, date, reason)?;
+    save(&scheduler).await
+}
+
+async fn delete_exception(
+    Path((index, exception_index)): Path<(usize, usize)>,
+    Form(form): Form<TargetForm>,
+) -> AdminResult<Redirect> {
+    let mut scheduler = SCHEDULER.get().unwrap().lock().await;
+    let schedule = schedule_at(&mut scheduler, index, form.original_id.as_deref())?;
+    let ScheduleType::Weekly { exception, .. } = &mut schedule.schedule_type else {return Err(Response::EventNotFound.into())};
+    if exception_index >= exception.len() {
+        return Err(Response::EventNotFound.into());
+    }
+    exception.remove(exception_index);
+    save(&scheduler).await
+}
+
+/// 締め切りを今にして、次のスケジュールの確認で結果を送る
+async fn close_attendance(Path(attendance_id): Path<String>) -> AdminResult<Redirect> {
+    //先にロックを取って、スケジュールの確認が済んだ時刻より後を締め切りにする (前だと送られなくなる)
+    let mut scheduler = SCHEDULER.get().unwrap().lock().await;
+    let now = Utc::now();
+    let updated = sqlx::query(
+        "update attendances set finishing_schedule = ? where attendance_id = ? and finishing_schedule > ?",
+    )
+    .bind(now)
+    .bind(&attendance_id)
+    .bind(now)
+    .execute(DB.get().unwrap())
+    .await
+    .map_err(internal_error)?;
+    if updated.rows_affected() == 0 {
+        return Err(Response::EventNotFound.into());
+    }
+
+    for schedule in scheduler.schedules_mut() {
+        let Todo::SendAttendanceInfo { attendance_id: ref id } = schedule.todo else {continue};
+        if *id == attendance_id {
+            schedule.schedule_type = ScheduleType::OneTime { datetime: now };
+        }
+    }
+    save(&scheduler).await
+}
+
+#[derive(Deserialize)]
+pub struct PollForm {
+    name: String,
+    datetime: String,
+    capacity: Option<String>,
+    template: Option<String>,
+}
+
+/// スケジュールを待たずに今すぐ出欠確認を送る
+async fn trigger_poll(Form(form): Form<PollForm>) -> AdminResult<Redirect> {
+    let name = form.name.trim();
+    if name.is_empty() {
+        return Err(Response::NotEnoughArgment.into());
+    }
+    let finishing_time = parse_local_input(&form.datetime)?;
+    if finishing_time < Utc::now() {
+        return Err(Response::PassedDate.into());
+    }
+    let capacity: Option<u32> = parse_optional(&form.capacity)?;
+    let template = get_event_template(template_from_form(&form.template)?.as_deref());
+    let schedule = create_attendance_check(finishing_time, name, capacity, &template).await;
+    let mut scheduler = SCHEDULER.get().unwrap().lock().await;
+    scheduler.push(schedule).await;
+    save(&scheduler).await
+}
+
+#[test]
+fn schedule_from_form_test() {
+    let form = ScheduleForm {
+        original_id: None,
+        id: "四谷練".to_string(),
+        kind: "weekly".to_string(),
+        weekday: Some("Mon".to_string()),
+        time: Some("10:00".to_string()),
+        datetime: None,
+        hour: Some("6".to_string()),
+        capacity: Some("".to_string()),
+        template: Some("".to_string()),
+        paused: None,
+    };
+    let schedule = schedule_from_form(&form).unwrap();
+    assert!(matches!(
+        schedule.schedule_type,
+        ScheduleType::Weekly { weekday: Weekday::Mon, .. }
+    ));
+    assert!(matches!(
+        schedule.todo,
+        Todo::CreateAttendanceCheck {
+            hour: Some(6),
+            capacity: None,
+            template: None
+        }
+    ));
+
+    let form = ScheduleForm {
+        kind: "once".to_string(),
+        datetime: Some("2000-01-01T10:00".to_string()),
+        ..form
+    };
+    assert!(matches!(schedule_from_form(&form), Err(Response::PassedDate)));
+}
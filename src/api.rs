@@ -0,0 +1,207 @@
+use super::*;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_PER_PAGE: i64 = 20;
+const MAX_PER_PAGE: i64 = 100;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/api/attendances", routing::get(list_attendances))
+        .route("/api/attendances/:id", routing::get(get_attendance))
+        .route("/api/attendances/:id/votes", routing::get(get_votes))
+        .layer(middleware::from_fn(require_admin))
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatusCounts {
+    pub attend: i64,
+    pub holding: i64,
+    pub absent: i64,
+    pub waiting: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AttendanceSummary {
+    pub attendance_id: String,
+    pub description: String,
+    pub group_id: String,
+    pub finishing_time: DateTime<Utc>,
+    pub capacity: Option<i64>,
+    pub counts: StatusCounts,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AttendanceList {
+    pub attendances: Vec<AttendanceSummary>,
+    pub page: i64,
+    pub per_page: i64,
+    pub total: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Vote {
+    pub user_id: String,
+    pub display_name: String,
+    pub status: String,
+    pub voted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+}
+
+//日付は日本時間の0時から数える
+fn local_midnight(date: NaiveDate) -> DateTime<Utc> {
+    let local = date
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_local_timezone(*TIMEZONE)
+        .unwrap();
+    DateTime::<Utc>::from_utc(local.naive_utc(), Utc)
+}
+
+/// 期間で絞り込むための境界。toはその日の終わりまで含む
+pub fn date_range(
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+) -> (Option<DateTime<Utc>>, Option<DateTime<Utc>>) {
+    (
+        from.map(local_midnight),
+        to.map(|to| local_midnight(to) + Duration::days(1)),
+    )
+}
+
+type AttendanceRow = (String, String, String, DateTime<Utc>, Option<i64>);
+
+async fn to_summary(
+    (attendance_id, description, group_id, finishing_time, capacity): AttendanceRow,
+) -> AttendanceSummary {
+    let counts = StatusCounts {
+        attend: count_status(&attendance_id, "attend").await.unwrap_or_default(),
+        holding: count_status(&attendance_id, "holding").await.unwrap_or_default(),
+        absent: count_status(&attendance_id, "absent").await.unwrap_or_default(),
+        waiting: count_status(&attendance_id, "waiting").await.unwrap_or_default(),
+    };
+    AttendanceSummary {
+        attendance_id,
+        description,
+        group_id,
+        finishing_time,
+        capacity,
+        counts,
+    }
+}
+
+/// 期間内の出欠を新しい順に集計付きで返す
+pub async fn fetch_summaries(
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<AttendanceSummary>> {
+    let rows: Vec<AttendanceRow> = sqlx::query_as(
+        "select attendance_id,description,group_id,finishing_schedule,capacity from attendances \
+         where (?1 is null or finishing_schedule >= ?1) and (?2 is null or finishing_schedule < ?2) \
+         order by finishing_schedule desc limit ?3 offset ?4",
+    )
+    .bind(from)
+    .bind(to)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(DB.get().unwrap())
+    .await?;
+
+    let mut summaries = vec![];
+    for row in rows {
+        summaries.push(to_summary(row).await);
+    }
+    Ok(summaries)
+}
+
+async fn list_attendances(
+    Query(query): Query<ListQuery>,
+) -> std::result::Result<Json<AttendanceList>, StatusCode> {
+    let (from, to) = date_range(query.from, query.to);
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query
+        .per_page
+        .unwrap_or(DEFAULT_PER_PAGE)
+        .clamp(1, MAX_PER_PAGE);
+    let offset = (page - 1)
+        .checked_mul(per_page)
+        .ok_or(StatusCode::BAD_REQUEST)?;
+
+    let total: i64 = sqlx::query_scalar(
+        "select count(*) from attendances \
+         where (?1 is null or finishing_schedule >= ?1) and (?2 is null or finishing_schedule < ?2)",
+    )
+    .bind(from)
+    .bind(to)
+    .fetch_one(DB.get().unwrap())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let attendances = fetch_summaries(from, to, per_page, offset)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(AttendanceList {
+        attendances,
+        page,
+        per_page,
+        total,
+    }))
+}
+
+async fn fetch_attendance_row(attendance_id: &str) -> std::result::Result<AttendanceRow, StatusCode> {
+    sqlx::query_as(
+        "select attendance_id,description,group_id,finishing_schedule,capacity from attendances where attendance_id = ?",
+    )
+    .bind(attendance_id)
+    .fetch_optional(DB.get().unwrap())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn get_attendance(
+    Path(attendance_id): Path<String>,
+) -> std::result::Result<Json<AttendanceSummary>, StatusCode> {
+    let row = fetch_attendance_row(&attendance_id).await?;
+    Ok(Json(to_summary(row).await))
+}
+
+async fn get_votes(
+    Path(attendance_id): Path<String>,
+) -> std::result::Result<Json<Vec<Vote>>, StatusCode> {
+    let (_, _, group_id, _, _) = fetch_attendance_row(&attendance_id).await?;
+    let rows: Vec<(String, String)> = sqlx::query_as(&format!(
+        "select user_id,status from {attendance_id} order by rowid"
+    ))
+    .fetch_all(DB.get().unwrap())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let history = get_vote_history(&attendance_id).await;
+
+    let mut votes = vec![];
+    for (user_id, status) in rows {
+        //履歴が無い古い投票は時刻が分からない
+        let voted_at = history
+            .iter()
+            .rev()
+            .find(|record| record.user_id == user_id)
+            .map(|record| record.voted_at);
+        votes.push(Vote {
+            display_name: get_display_name(&user_id, &group_id).await,
+            user_id,
+            status,
+            voted_at,
+        });
+    }
+    Ok(Json(votes))
+}
+
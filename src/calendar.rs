@@ -0,0 +1,152 @@
+use super::*;
+use axum::http::header;
+use axum::response::IntoResponse;
+
+//何週間先までの予定を配信するか
+const CALENDAR_WEEKS: i64 = 8;
+//過去の出欠も少しだけ残しておく
+const CALENDAR_PAST_DAYS: i64 = 30;
+//終了時刻が分からないので、開催時刻からこの長さの予定にする
+const EVENT_DURATION_HOURS: i64 = 3;
+//RFC 5545では1行を75バイトまでにする
+const MAX_LINE_OCTETS: usize = 75;
+
+#[derive(Debug)]
+pub struct CalendarEvent {
+    pub summary: String,
+    pub start: DateTime<Utc>,
+}
+
+fn escape_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+//長い行は改行と空白で折り返す。日本語の文字の途中では切らない
+fn fold_line(line: &str) -> String {
+    let mut folded = String::new();
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > MAX_LINE_OCTETS {
+            folded.push_str("\r\n ");
+            width = 1;
+        }
+        folded.push(c);
+        width += c.len_utf8();
+    }
+    folded
+}
+
+fn format_datetime(datetime: &DateTime<Utc>) -> String {
+    datetime.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+pub fn to_ics(group_id: &str, events: &[CalendarEvent], now: &DateTime<Utc>) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//bridge_line_bot//JA".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+    ];
+    for event in events {
+        lines.push("BEGIN:VEVENT".to_string());
+        //同じグループで同じ時刻のイベントは同じ予定として扱う
+        lines.push(format!(
+            "UID:{}-{}@{}",
+            format_datetime(&event.start),
+            group_id,
+            SETTINGS.HOST
+        ));
+        lines.push(format!("DTSTAMP:{}", format_datetime(now)));
+        lines.push(format!("DTSTART:{}", format_datetime(&event.start)));
+        lines.push(format!(
+            "DTEND:{}",
+            format_datetime(&(event.start + Duration::hours(EVENT_DURATION_HOURS)))
+        ));
+        lines.push(format!("SUMMARY:{}", escape_text(&event.summary)));
+        lines.push("END:VEVENT".to_string());
+    }
+    lines.push("END:VCALENDAR".to_string());
+    lines
+        .iter()
+        .map(|line| fold_line(line))
+        .collect::<Vec<_>>()
+        .join("\r\n")
+        + "\r\n"
+}
+
+/// 出欠確認を送ったイベントと、これから送る予定のイベントを合わせる
+pub async fn calendar_events(group_id: &str) -> Vec<CalendarEvent> {
+    let now = Utc::now();
+    let from = now - Duration::days(CALENDAR_PAST_DAYS);
+    let until = now + Duration::weeks(CALENDAR_WEEKS);
+
+    let attendances: Vec<(String, DateTime<Utc>)> = sqlx::query_as(
+        "select description,finishing_schedule from attendances \
+         where group_id = ? and finishing_schedule >= ? and finishing_schedule < ? \
+         order by finishing_schedule",
+    )
+    .bind(group_id)
+    .bind(from)
+    .bind(until)
+    .fetch_all(DB.get().unwrap())
+    .await
+    .unwrap_or_default();
+    let mut events: Vec<CalendarEvent> = attendances
+        .into_iter()
+        .map(|(summary, start)| CalendarEvent { summary, start })
+        .collect();
+
+    //スケジュールは全て紐付けられたグループに送られる
+    if group_id == SETTINGS.BINDED_GROUP_ID {
+        let scheduled = SCHEDULER
+            .get()
+            .unwrap()
+            .lock()
+            .await
+            .upcoming_events(&now, &until);
+        for (summary, start) in scheduled {
+            if !events.iter().any(|e| e.start == start) {
+                events.push(CalendarEvent { summary, start });
+            }
+        }
+    }
+    events.sort_by_key(|e| e.start);
+    events
+}
+
+pub async fn calendar_feed(Path(file): Path<String>) -> axum::response::Response {
+    let Some(group_id) = file.strip_suffix(".ics") else {return StatusCode::NOT_FOUND.into_response()};
+    let known: bool = sqlx::query_scalar("select count(*) > 0 from attendances where group_id = ?")
+        .bind(group_id)
+        .fetch_one(DB.get().unwrap())
+        .await
+        .unwrap_or_default();
+    if !known && group_id != SETTINGS.BINDED_GROUP_ID {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let events = calendar_events(group_id).await;
+    (
+        [(header::CONTENT_TYPE, "text/calendar; charset=utf-8")],
+        to_ics(group_id, &events, &Utc::now()),
+    )
+        .into_response()
+}
+
+#[test]
+fn escape_text_test() {
+    assert_eq!(escape_text("a,b;c\\d\ne"), "a\\,b\\;c\\\\d\\ne");
+}
+
+#[test]
+fn fold_line_test() {
+    let line = format!("SUMMARY:{}", escape_text(&"2/20(月)四谷練、初心者歓迎です。".repeat(5)));
+    let folded = fold_line(&line);
+    assert!(folded.split("\r\n").all(|l| l.len() <= MAX_LINE_OCTETS));
+    assert!(folded.split("\r\n").skip(1).all(|l| l.starts_with(' ')));
+    assert_eq!(folded.replace("\r\n ", ""), line);
+    assert_eq!(fold_line("VERSION:2.0"), "VERSION:2.0");
+}
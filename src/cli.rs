@@ -0,0 +1,295 @@
+use super::*;
+use chrono::Weekday;
+use clap::{Parser, Subcommand};
+use std::io::Write;
+
+#[derive(Parser)]
+#[command(name = "bridge_line_bot", about = "ブリッジサークルの出欠確認LINEボット")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// サーバーを起動する (サブコマンドを省略したときもこれ)
+    Serve,
+    /// データベースに足りないテーブルやカラムを追加する
+    Migrate,
+    /// schedule.jsonのスケジュールを操作する。サーバーは止めてから使う
+    Schedule {
+        #[command(subcommand)]
+        command: ScheduleCommand,
+    },
+    /// 出欠を確認する
+    Attendance {
+        #[command(subcommand)]
+        command: AttendanceCommand,
+    },
+    /// 動作確認のメッセージを送る
+    SendTestMessage {
+        text: String,
+        /// 送り先 (省略すると紐付けられたグループ)
+        #[arg(long)]
+        to: Option<String>,
+    },
+    /// 期間内の出欠をCSVで書き出す
+    Export {
+        /// 開始日 (例: 2023/02/01)
+        #[arg(long, value_parser = parse_date)]
+        from: Option<NaiveDate>,
+        /// 終了日 (その日を含む)
+        #[arg(long, value_parser = parse_date)]
+        to: Option<NaiveDate>,
+        /// 書き出すファイル (省略すると標準出力)
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ScheduleCommand {
+    /// 登録されているスケジュールを一覧する
+    List,
+    /// 出欠確認を送るスケジュールを追加する
+    Add {
+        /// イベント名
+        name: String,
+        /// 毎週送る曜日 (例: mon)
+        #[arg(long, value_parser = parse_weekday, requires = "time", conflicts_with = "at")]
+        weekly: Option<Weekday>,
+        /// 毎週送る時刻 (例: 10:00)
+        #[arg(long, value_parser = parse_time)]
+        time: Option<NaiveTime>,
+        /// 一度だけ送る日時 (例: "2023/02/20 10:00")
+        #[arg(long, value_parser = parse_datetime, required_unless_present = "weekly")]
+        at: Option<DateTime<Utc>>,
+        /// 送ってから締め切りまでの時間 (無ければテンプレートの値か6時間)
+        #[arg(long)]
+        hour: Option<i64>,
+        /// 定員
+        #[arg(long)]
+        capacity: Option<u32>,
+        /// 設定のEVENT_TEMPLATESで定義したテンプレート
+        #[arg(long)]
+        template: Option<String>,
+    },
+    /// スケジュールを削除する
+    Remove { name: String },
+}
+
+#[derive(Subcommand)]
+pub enum AttendanceCommand {
+    /// 出欠の集計と投票したメンバーを表示する
+    Show { attendance_id: String },
+}
+
+fn parse_date(text: &str) -> std::result::Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(text, "%Y/%m/%d").map_err(|e| e.to_string())
+}
+
+fn parse_time(text: &str) -> std::result::Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(text, "%H:%M").map_err(|e| e.to_string())
+}
+
+fn parse_weekday(text: &str) -> std::result::Result<Weekday, String> {
+    text.parse().map_err(|_| format!("unknown weekday: {text}"))
+}
+
+//日本時間として読む
+fn parse_datetime(text: &str) -> std::result::Result<DateTime<Utc>, String> {
+    let local = NaiveDateTime::parse_from_str(text, "%Y/%m/%d %H:%M").map_err(|e| e.to_string())?;
+    let local = local
+        .and_local_timezone(*TIMEZONE)
+        .single()
+        .ok_or_else(|| format!("invalid datetime: {text}"))?;
+    Ok(local.with_timezone(&Utc))
+}
+
+pub async fn run_command(command: Command) -> Result<()> {
+    match command {
+        Command::Serve => run_server().await,
+        Command::Migrate => {
+            initialize_db().await?;
+            println!("migrated");
+            Ok(())
+        }
+        Command::Schedule { command } => {
+            initialize_db().await?;
+            schedule_command(command).await
+        }
+        Command::Attendance {
+            command: AttendanceCommand::Show { attendance_id },
+        } => {
+            initialize_db().await?;
+            show_attendance(&attendance_id).await
+        }
+        Command::SendTestMessage { text, to } => {
+            let message = PushMessage {
+                to: to.unwrap_or_else(|| SETTINGS.BINDED_GROUP_ID.clone()),
+                messages: vec![Box::new(SimpleMessage::new(&text))],
+            };
+            message.send().await;
+            Ok(())
+        }
+        Command::Export { from, to, output } => {
+            initialize_db().await?;
+            let csv = to_csv(&export_rows(from, to).await?)?;
+            match output {
+                Some(path) => fs::write(path, csv)?,
+                None => std::io::stdout().write_all(&csv)?,
+            }
+            Ok(())
+        }
+    }
+}
+
+fn format_local(datetime: &DateTime<Utc>) -> String {
+    datetime.with_timezone(&*TIMEZONE).format("%Y/%m/%d %H:%M").to_string()
+}
+
+pub fn describe_schedule(schedule: &Schedule) -> String {
+    let when = match &schedule.schedule_type {
+        ScheduleType::OneTime { datetime } => format_local(datetime),
+        ScheduleType::Weekly {
+            weekday,
+            time,
+            exception,
+        } => {
+            let mut text = format!("毎週{}曜 {}", weekday_to_jp(*weekday), time.format("%H:%M"));
+            if !exception.is_empty() {
+                text += &format!(" (休み{}回)", exception.len());
+            }
+            text
+        }
+    };
+    let todo = match &schedule.todo {
+        Todo::CreateAttendanceCheck {
+            capacity, template, ..
+        } => {
+            let hour = schedule.todo.deadline_hours().unwrap_or_default();
+            let mut text = format!("出欠確認 締め切り{hour}時間後");
+            if let Some(capacity) = capacity {
+                text += &format!(" 定員{capacity}人");
+            }
+            if let Some(template) = template {
+                text += &format!(" テンプレート{template}");
+            }
+            text
+        }
+        todo => todo.name().to_string(),
+    };
+    let paused = if schedule.paused { " [停止中]" } else { "" };
+    format!("{}\t{}\t{}{}", schedule.id, when, todo, paused)
+}
+
+async fn schedule_command(command: ScheduleCommand) -> Result<()> {
+    let mut scheduler = Scheduler::from_file("schedule.json").await?;
+    match command {
+        ScheduleCommand::List => {
+            for schedule in scheduler.schedules() {
+                println!("{}", describe_schedule(schedule));
+            }
+            return Ok(());
+        }
+        ScheduleCommand::Add {
+            name,
+            weekly,
+            time,
+            at,
+            hour,
+            capacity,
+            template,
+        } => {
+            if let Some(template) = &template {
+                if !SETTINGS.EVENT_TEMPLATES.contains_key(template) {
+                    return Err(format!("event template not found: {template}").into());
+                }
+            }
+            let schedule_type = match (weekly, time, at) {
+                (Some(weekday), Some(time), _) => ScheduleType::Weekly {
+                    weekday,
+                    time,
+                    exception: vec![],
+                },
+                (_, _, Some(datetime)) => {
+                    if datetime < Utc::now() {
+                        return Err("the datetime has already passed".into());
+                    }
+                    ScheduleType::OneTime { datetime }
+                }
+                _ => return Err("specify either --weekly and --time, or --at".into()),
+            };
+            let schedule = Schedule {
+                id: name,
+                schedule_type,
+                todo: Todo::CreateAttendanceCheck {
+                    hour,
+                    capacity,
+                    template,
+                },
+                paused: false,
+            };
+            println!("added: {}", describe_schedule(&schedule));
+            scheduler.push(schedule).await;
+        }
+        ScheduleCommand::Remove { name } => {
+            let Some(schedule) = scheduler.remove(&name) else {return Err(format!("schedule not found: {name}").into())};
+            println!("removed: {}", describe_schedule(&schedule));
+        }
+    }
+    scheduler.save_shedule("schedule.json").await
+}
+
+async fn show_attendance(attendance_id: &str) -> Result<()> {
+    let (description, group_id, finishing_time, capacity): (String, String, DateTime<Utc>, Option<i64>) =
+        sqlx::query_as(
+            "select description,group_id,finishing_schedule,capacity from attendances where attendance_id = ?",
+        )
+        .bind(attendance_id)
+        .fetch_optional(DB.get().unwrap())
+        .await?
+        .ok_or_else(|| format!("attendance not found: {attendance_id}"))?;
+
+    println!("{description}");
+    println!("締め切り: {}", format_local(&finishing_time));
+    if let Some(capacity) = capacity {
+        println!("定員: {capacity}人");
+    }
+    let attendance = get_attendance_status(attendance_id).await;
+    for (status, users) in [
+        ("attend", &attendance.attend),
+        ("holding", &attendance.holding),
+        ("absent", &attendance.absent),
+        ("waiting", &attendance.waiting),
+    ] {
+        println!("{} ({}人)", status_to_jp(status), users.len());
+        for user_id in users {
+            println!("  {}", get_display_name(user_id, &group_id).await);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn cli_parse_test() {
+    let cli = Cli::try_parse_from([
+        "bridge_line_bot",
+        "schedule",
+        "add",
+        "四谷練",
+        "--weekly",
+        "mon",
+        "--time",
+        "10:00",
+    ])
+    .unwrap();
+    let Some(Command::Schedule { command: ScheduleCommand::Add { weekly, time, hour, .. } }) = cli.command else {panic!()};
+    assert_eq!(weekly, Some(Weekday::Mon));
+    assert_eq!(time, NaiveTime::from_hms_opt(10, 0, 0));
+    assert_eq!(hour, None);
+
+    //日時も曜日も無ければエラー
+    assert!(Cli::try_parse_from(["bridge_line_bot", "schedule", "add", "四谷練"]).is_err());
+    assert!(Cli::try_parse_from(["bridge_line_bot"]).unwrap().command.is_none());
+}
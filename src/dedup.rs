@@ -0,0 +1,126 @@
+use super::*;
+use sqlx::SqlitePool;
+
+//処理済みのwebhookEventIdを残しておく期間
+const RETENTION_DAYS: i64 = 7;
+
+pub async fn create_table(pool: &SqlitePool) -> Result<()> {
+    sqlx::query(
+        "create table if not exists webhook_events(event_id string primary key,expires_at datetime)",
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// webhookEventIdと再送かどうか
+pub fn event_meta(event: &Value) -> (Option<&str>, bool) {
+    let event_id = event.get("webhookEventId").and_then(|id| id.as_str());
+    let redelivery = event
+        .get("deliveryContext")
+        .and_then(|c| c.get("isRedelivery"))
+        .and_then(|r| r.as_bool())
+        .unwrap_or(false);
+    (event_id, redelivery)
+}
+
+/// イベントを処理済みとして記録する。既に記録されていればfalseを返す
+pub async fn claim_event(pool: &SqlitePool, event_id: &str, now: DateTime<Utc>) -> Result<bool> {
+    sqlx::query("delete from webhook_events where expires_at < ?")
+        .bind(now)
+        .execute(pool)
+        .await?;
+    let result = sqlx::query("insert or ignore into webhook_events(event_id,expires_at) values(?,?)")
+        .bind(event_id)
+        .bind(now + Duration::days(RETENTION_DAYS))
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+/// 再送されてきたイベントを既に処理していたらtrue
+pub async fn is_duplicate(pool: &SqlitePool, event: &Value, now: DateTime<Utc>) -> bool {
+    let (Some(event_id), redelivery) = event_meta(event) else {return false};
+    match claim_event(pool, event_id, now).await {
+        Ok(true) => false,
+        Ok(false) => {
+            if !redelivery {
+                tracing::warn!(event_id, "duplicated webhook event without redelivery flag");
+            }
+            true
+        }
+        //記録できなくても取りこぼすよりは処理する
+        Err(e) => {
+            tracing::warn!(event_id, error = %e, "failed to record webhook event");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+async fn test_pool() -> SqlitePool {
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    create_table(&pool).await.unwrap();
+    pool
+}
+
+#[test]
+fn event_meta_test() {
+    let event: Value = serde_json::from_str(
+        r#"{"type":"message","webhookEventId":"01FZ74A0TDDPYRVKNK77XKC3ZR","deliveryContext":{"isRedelivery":true}}"#,
+    )
+    .unwrap();
+    assert_eq!(event_meta(&event), (Some("01FZ74A0TDDPYRVKNK77XKC3ZR"), true));
+    let event: Value = serde_json::from_str(r#"{"type":"message"}"#).unwrap();
+    assert_eq!(event_meta(&event), (None, false));
+}
+
+#[tokio::test]
+async fn redelivered_event_is_skipped_test() {
+    let pool = test_pool().await;
+    let now = Utc::now();
+    let first: Value = serde_json::from_str(
+        r#"{"webhookEventId":"EVENT1","deliveryContext":{"isRedelivery":false}}"#,
+    )
+    .unwrap();
+    let redelivered: Value = serde_json::from_str(
+        r#"{"webhookEventId":"EVENT1","deliveryContext":{"isRedelivery":true}}"#,
+    )
+    .unwrap();
+    let other: Value = serde_json::from_str(
+        r#"{"webhookEventId":"EVENT2","deliveryContext":{"isRedelivery":true}}"#,
+    )
+    .unwrap();
+
+    assert!(!is_duplicate(&pool, &first, now).await);
+    assert!(is_duplicate(&pool, &redelivered, now).await);
+    //初回の配信に失敗して再送で初めて届いた場合は処理する
+    assert!(!is_duplicate(&pool, &other, now).await);
+}
+
+#[tokio::test]
+async fn expired_event_is_forgotten_test() {
+    let pool = test_pool().await;
+    let now = Utc::now();
+    assert!(claim_event(&pool, "EVENT1", now).await.unwrap());
+    assert!(!claim_event(&pool, "EVENT1", now + Duration::days(1)).await.unwrap());
+    assert!(claim_event(&pool, "EVENT1", now + Duration::days(RETENTION_DAYS + 1)).await.unwrap());
+
+    let count: i64 = sqlx::query_scalar("select count(*) from webhook_events")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(count, 1);
+}
+
+#[tokio::test]
+async fn event_without_id_is_processed_test() {
+    let pool = test_pool().await;
+    let event: Value = serde_json::from_str(r#"{"type":"message"}"#).unwrap();
+    assert!(!is_duplicate(&pool, &event, Utc::now()).await);
+    assert!(!is_duplicate(&pool, &event, Utc::now()).await);
+}
@@ -0,0 +1,116 @@
+use super::*;
+use std::path::Path;
+
+//テンプレートが指定されていないときの出欠確認の文面
+const DEFAULT_DESCRIPTION: &str = "{{ month }}/{{ day }}({{ weekday }}){{ name }}";
+const DEFAULT_FLEX: &str = "vote_flex_message.json";
+
+/// 設定のEVENT_TEMPLATESで名前を付けて定義する、イベントごとの出欠確認の内容
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+pub struct EventTemplate {
+    /// 出欠確認の文面。name, month, day, weekday, time, venue, fee, capacityが使える
+    pub description: Option<String>,
+    pub venue: Option<String>,
+    pub fee: Option<String>,
+    /// スケジュールで定員を指定していなければこの定員にする
+    pub capacity: Option<u32>,
+    /// 卓が立つ人数。無ければQUORUM
+    pub quorum: Option<i64>,
+    /// 出欠確認のカードのjson。%VENUE%と%FEE%も置き換えられる
+    pub flex: Option<PathBuf>,
+    /// スケジュールで締め切りまでの時間を指定していなければこの時間にする
+    pub hour: Option<i64>,
+}
+
+impl EventTemplate {
+    pub fn description_source(&self) -> &str {
+        self.description.as_deref().unwrap_or(DEFAULT_DESCRIPTION)
+    }
+
+    pub fn flex_path(&self) -> &Path {
+        self.flex.as_deref().unwrap_or(Path::new(DEFAULT_FLEX))
+    }
+
+    pub fn describe(&self, name: &str, finishing_time: &DateTime<Utc>) -> Result<String> {
+        let local = finishing_time.with_timezone(&*TIMEZONE);
+        let context = minijinja::context! {
+            name,
+            month => local.month(),
+            day => local.day(),
+            weekday => weekday_to_jp(local.weekday()),
+            time => local.format("%H:%M").to_string(),
+            venue => self.venue,
+            fee => self.fee,
+            capacity => self.capacity,
+        };
+        //LINEに送る文面なのでHTMLのエスケープはしない
+        Ok(minijinja::Environment::new().render_str(self.description_source(), context)?)
+    }
+}
+
+/// 名前の付いたテンプレートを探す。無ければ今まで通りの内容にする
+pub fn get_event_template(name: Option<&str>) -> EventTemplate {
+    let Some(name) = name else {return EventTemplate::default()};
+    match SETTINGS.EVENT_TEMPLATES.get(name) {
+        Some(template) => template.clone(),
+        None => {
+            tracing::warn!(template = name, "unknown event template");
+            EventTemplate::default()
+        }
+    }
+}
+
+/// 設定を読んだときに、文面とカードが壊れていないか確かめる
+pub fn check_event_template(name: &str, template: &EventTemplate) -> Vec<String> {
+    let mut problems = vec![];
+    if let Err(e) = minijinja::Environment::new().template_from_str(template.description_source()) {
+        problems.push(format!("EVENT_TEMPLATES.{name}.description is invalid: {e}"));
+    }
+    if let Some(flex) = &template.flex {
+        match fs::read_to_string(flex) {
+            Ok(text) => {
+                if let Err(e) = serde_json::from_str::<Value>(&text) {
+                    problems.push(format!(
+                        "EVENT_TEMPLATES.{name}.flex {} is not valid JSON: {e}",
+                        flex.display()
+                    ));
+                }
+            }
+            Err(_) => problems.push(format!(
+                "EVENT_TEMPLATES.{name}.flex {} does not exist",
+                flex.display()
+            )),
+        }
+    }
+    problems
+}
+
+#[test]
+fn describe_test() {
+    //2023/2/20 19:00 (日本時間) は月曜日
+    let finishing_time = NaiveDate::from_ymd_opt(2023, 2, 20)
+        .unwrap()
+        .and_hms_opt(10, 0, 0)
+        .unwrap()
+        .and_local_timezone(Utc)
+        .unwrap();
+    let default = EventTemplate::default();
+    assert_eq!(default.describe("四谷練", &finishing_time).unwrap(), "2/20(月)四谷練");
+
+    let template = EventTemplate {
+        description: Some("{{ month }}/{{ day }}({{ weekday }}) {{ time }} {{ name }} @{{ venue }} {{ fee }}".to_string()),
+        venue: Some("渋谷区民会館".to_string()),
+        fee: Some("500円".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(
+        template.describe("渋谷練", &finishing_time).unwrap(),
+        "2/20(月) 19:00 渋谷練 @渋谷区民会館 500円"
+    );
+    assert!(check_event_template("broken", &EventTemplate {
+        description: Some("{{ name".to_string()),
+        ..Default::default()
+    })
+    .iter()
+    .any(|problem| problem.contains("broken")));
+}
@@ -0,0 +1,150 @@
+use super::*;
+use axum::http::{header, Request};
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use std::collections::HashMap;
+
+#[derive(Debug, serde::Serialize)]
+pub struct ExportRow {
+    pub date: String,
+    pub description: String,
+    pub attendance_id: String,
+    pub user_id: String,
+    pub display_name: String,
+    pub status: String,
+}
+
+/// 期間内の全ての出欠について、投票したメンバーごとに1行ずつ並べる
+pub async fn export_rows(from: Option<NaiveDate>, to: Option<NaiveDate>) -> Result<Vec<ExportRow>> {
+    let (from, to) = api::date_range(from, to);
+    let attendances: Vec<(String, String, String, DateTime<Utc>)> = sqlx::query_as(
+        "select attendance_id,description,group_id,finishing_schedule from attendances \
+         where (?1 is null or finishing_schedule >= ?1) and (?2 is null or finishing_schedule < ?2) \
+         order by finishing_schedule",
+    )
+    .bind(from)
+    .bind(to)
+    .fetch_all(DB.get().unwrap())
+    .await?;
+
+    let mut names: HashMap<String, String> = HashMap::new();
+    let mut rows = vec![];
+    for (attendance_id, description, group_id, finishing_time) in attendances {
+        let votes: Vec<(String, String)> = sqlx::query_as(&format!(
+            "select user_id,status from {attendance_id} order by rowid"
+        ))
+        .fetch_all(DB.get().unwrap())
+        .await
+        .unwrap_or_default();
+        for (user_id, status) in votes {
+            if !names.contains_key(&user_id) {
+                let name = get_display_name(&user_id, &group_id).await;
+                names.insert(user_id.clone(), name);
+            }
+            rows.push(ExportRow {
+                date: finishing_time
+                    .with_timezone(&*TIMEZONE)
+                    .format("%Y/%m/%d %H:%M")
+                    .to_string(),
+                description: description.clone(),
+                attendance_id: attendance_id.clone(),
+                display_name: names[&user_id].clone(),
+                user_id,
+                status: status_to_jp(&status).to_string(),
+            });
+        }
+    }
+    Ok(rows)
+}
+
+pub fn to_csv(rows: &[ExportRow]) -> Result<Vec<u8>> {
+    //Excelで開いても文字化けしないようにBOMを付ける
+    let mut writer = csv::Writer::from_writer(b"\xEF\xBB\xBF".to_vec());
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    Ok(writer.into_inner()?)
+}
+
+#[derive(serde::Deserialize)]
+pub struct ExportQuery {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    pub token: Option<String>,
+}
+
+/// 管理者か、その期間のエクスポート用トークンを持っていればダウンロードできる
+pub async fn require_export_access<B>(request: Request<B>, next: Next<B>) -> axum::response::Response {
+    let signed = Query::<ExportQuery>::try_from_uri(request.uri())
+        .ok()
+        .is_some_and(|Query(query)| {
+            query
+                .token
+                .as_deref()
+                .is_some_and(|token| verify_export_token(query.from, query.to, token))
+        });
+    if signed || is_admin_request(&request) {
+        next.run(request).await
+    } else {
+        StatusCode::UNAUTHORIZED.into_response()
+    }
+}
+
+pub async fn export_csv(Query(query): Query<ExportQuery>) -> axum::response::Response {
+    let csv = match export_rows(query.from, query.to).await {
+        Ok(rows) => to_csv(&rows),
+        Err(e) => Err(e),
+    };
+    match csv {
+        Ok(csv) => (
+            [
+                (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"attendances.csv\"",
+                ),
+            ],
+            csv,
+        )
+            .into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// 管理者にだけ期間を指定したダウンロード用のURLを返す。
+/// チャットの履歴に残るので、管理者キーではなくその期間だけのすぐ切れるトークンを付ける
+pub(crate) async fn export_command(event: &Value, args: Vec<&str>) -> Response {
+    let Some(user_id) = event.get("source").and_then(|s| s.get("userId")).and_then(|u| u.as_str()) else {return Response::NotEnoughArgment};
+    if !SETTINGS.ADMIN_USER_IDS.iter().any(|id| id == user_id) {
+        return Response::PermissionDenied;
+    }
+    let Some(&from) = args.get(1) else {return Response::NotEnoughArgment};
+    let Some(&to) = args.get(2) else {return Response::NotEnoughArgment};
+    let (Ok(from), Ok(to)) = (NaiveDate::parse_from_str(from, "%Y/%m/%d"), NaiveDate::parse_from_str(to, "%Y/%m/%d")) else {return Response::DateParseError};
+
+    Response::Success(format!(
+        "https://{}/api/export.csv?from={}&to={}&token={}",
+        SETTINGS.HOST,
+        from.format("%Y-%m-%d"),
+        to.format("%Y-%m-%d"),
+        sign_export_token(Some(from), Some(to), Utc::now())
+    ))
+}
+
+#[test]
+fn to_csv_test() {
+    let rows = vec![ExportRow {
+        date: "2023/02/20 19:00".to_string(),
+        description: "2/20(月)四谷練".to_string(),
+        attendance_id: "attendance1".to_string(),
+        user_id: "U1".to_string(),
+        display_name: "山田, 太郎".to_string(),
+        status: "出席".to_string(),
+    }];
+    let csv = String::from_utf8(to_csv(&rows).unwrap()).unwrap();
+    assert_eq!(
+        csv,
+        "\u{feff}date,description,attendance_id,user_id,display_name,status\n\
+         2023/02/20 19:00,2/20(月)四谷練,attendance1,U1,\"山田, 太郎\",出席\n"
+    );
+}
@@ -0,0 +1,165 @@
+use super::*;
+
+//新しく入った人に知らせる予定の数
+const UPCOMING_EVENT_COUNT: usize = 5;
+
+fn group_id_of(event: &Value) -> Option<&str> {
+    event.get("source")?.get("groupId")?.as_str()
+}
+
+fn user_ids_of<'a>(event: &'a Value, key: &str) -> Vec<&'a str> {
+    event
+        .get(key)
+        .and_then(|e| e.get("members"))
+        .and_then(|m| m.as_array())
+        .map(|members| {
+            members
+                .iter()
+                .filter_map(|m| m.get("userId")?.as_str())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+async fn set_group_active(group_id: &str, active: bool) -> Result<()> {
+    sqlx::query(
+        "insert into groups(group_id,active,updated_at) values(?,?,?) \
+         on conflict(group_id) do update set active=excluded.active, updated_at=excluded.updated_at",
+    )
+    .bind(group_id)
+    .bind(active)
+    .bind(Utc::now())
+    .execute(DB.get().unwrap())
+    .await?;
+    Ok(())
+}
+
+//スケジュールは全て紐付けられたグループに送られるので、そのグループの出入りに合わせて止める。
+//管理画面で個別に止めた予定には触らない
+async fn set_schedules_suspended(group_id: &str, suspended: bool) {
+    if group_id != SETTINGS.BINDED_GROUP_ID {
+        return;
+    }
+    SCHEDULER
+        .get()
+        .unwrap()
+        .lock()
+        .await
+        .set_group_inactive(suspended);
+}
+
+//usage.txtが読めないときはビルド時の内容を送る
+const EMBEDDED_USAGE: &str = include_str!("../usage.txt");
+
+/// 使い方の文面。usage.txtを毎回読み直すので、再起動せずに編集できる
+pub fn usage_text() -> String {
+    fs::read_to_string("usage.txt").unwrap_or_else(|e| {
+        tracing::warn!(error = %e, "failed to read usage.txt");
+        EMBEDDED_USAGE.to_string()
+    })
+}
+
+pub async fn on_follow(event: &Value) -> Option<()> {
+    let user_id = event.get("source")?.get("userId")?.as_str()?;
+    let message = PushMessage {
+        to: user_id.to_owned(),
+        messages: vec![
+            Box::new(SimpleMessage::new("友だち追加ありがとうございます！")),
+            Box::new(SimpleMessage::new(&usage_text())),
+        ],
+    };
+    message.send().await;
+    Some(())
+}
+
+pub async fn on_join(event: &Value) -> Option<()> {
+    let group_id = group_id_of(event)?;
+    set_group_active(group_id, true).await.ok()?;
+    set_schedules_suspended(group_id, false).await;
+    let message = PushMessage {
+        to: group_id.to_owned(),
+        messages: vec![Box::new(SimpleMessage::new(
+            "招待ありがとうございます！「使い方」と送ると使い方が見れます",
+        ))],
+    };
+    message.send().await;
+    Some(())
+}
+
+pub async fn on_leave(event: &Value) -> Option<()> {
+    let group_id = group_id_of(event)?;
+    set_group_active(group_id, false).await.ok()?;
+    set_schedules_suspended(group_id, true).await;
+    Some(())
+}
+
+pub async fn on_member_joined(event: &Value) -> Option<()> {
+    let group_id = group_id_of(event)?;
+    let user_ids = user_ids_of(event, "joined");
+    for user_id in &user_ids {
+        let _ = sqlx::query(
+            "insert into members(group_id,user_id,joined_at) values(?,?,?) \
+             on conflict(group_id,user_id) do update set joined_at=excluded.joined_at, left_at=null",
+        )
+        .bind(group_id)
+        .bind(user_id)
+        .bind(Utc::now())
+        .execute(DB.get().unwrap())
+        .await;
+    }
+    if !SETTINGS.GREET_NEW_MEMBERS || user_ids.is_empty() {
+        return Some(());
+    }
+
+    let now = Utc::now();
+    let mut text = "ようこそ！".to_string();
+    let upcoming: Vec<CalendarEvent> = calendar_events(group_id)
+        .await
+        .into_iter()
+        .filter(|e| e.start >= now)
+        .take(UPCOMING_EVENT_COUNT)
+        .collect();
+    if !upcoming.is_empty() {
+        text += "\n今後の予定です";
+        for event in upcoming {
+            let start = event.start.with_timezone(&*TIMEZONE);
+            text += &format!(
+                "\n{}/{}({}) {} {}",
+                start.month(),
+                start.day(),
+                weekday_to_jp(start.weekday()),
+                start.format("%H:%M"),
+                event.summary
+            );
+        }
+    }
+    let message = PushMessage {
+        to: group_id.to_owned(),
+        messages: vec![Box::new(SimpleMessage::new(&text))],
+    };
+    message.send().await;
+    Some(())
+}
+
+pub async fn on_member_left(event: &Value) -> Option<()> {
+    let group_id = group_id_of(event)?;
+    for user_id in user_ids_of(event, "left") {
+        let _ = sqlx::query("update members set left_at = ? where group_id = ? and user_id = ?")
+            .bind(Utc::now())
+            .bind(group_id)
+            .bind(user_id)
+            .execute(DB.get().unwrap())
+            .await;
+    }
+    Some(())
+}
+
+#[test]
+fn user_ids_of_test() {
+    let event: Value = serde_json::from_str(
+        r#"{"type":"memberJoined","joined":{"members":[{"type":"user","userId":"U1"},{"type":"user","userId":"U2"}]}}"#,
+    )
+    .unwrap();
+    assert_eq!(user_ids_of(&event, "joined"), vec!["U1", "U2"]);
+    assert!(user_ids_of(&event, "left").is_empty());
+}
@@ -0,0 +1,156 @@
+use super::*;
+use axum::body::Body;
+use axum::http::Request;
+use axum::response::IntoResponse;
+use axum::Json;
+use sqlx::SqlitePool;
+use std::future::Future;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+//スケジュールの確認は5秒ごとなので、これより空いていたら止まっているとみなす
+const SCHEDULER_STALE_SECS: i64 = 60;
+const DATABASE_TIMEOUT_SECS: u64 = 2;
+
+//最後にスケジュールの確認が終わった時刻 (UNIX秒、0はまだ一度も動いていない)
+static LAST_TICK: AtomicI64 = AtomicI64::new(0);
+
+pub fn record_tick(now: DateTime<Utc>) {
+    LAST_TICK.store(now.timestamp(), Ordering::Relaxed);
+}
+
+pub fn last_tick() -> Option<DateTime<Utc>> {
+    match LAST_TICK.load(Ordering::Relaxed) {
+        0 => None,
+        timestamp => Utc.timestamp_opt(timestamp, 0).single(),
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+impl Check {
+    fn ok(detail: Option<String>) -> Self {
+        Check { ok: true, detail }
+    }
+    fn failed(detail: String) -> Self {
+        Check {
+            ok: false,
+            detail: Some(detail),
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct HealthReport {
+    pub ok: bool,
+    pub database: Check,
+    pub scheduler: Check,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_token: Option<Check>,
+}
+
+pub async fn check_database(pool: &SqlitePool) -> Check {
+    let query = sqlx::query_scalar::<_, i64>("select 1").fetch_one(pool);
+    match tokio::time::timeout(std::time::Duration::from_secs(DATABASE_TIMEOUT_SECS), query).await {
+        Ok(Ok(_)) => Check::ok(None),
+        Ok(Err(e)) => Check::failed(e.to_string()),
+        Err(_) => Check::failed("timed out".to_string()),
+    }
+}
+
+pub fn check_scheduler(last_tick: Option<DateTime<Utc>>, now: DateTime<Utc>) -> Check {
+    let Some(last_tick) = last_tick else {return Check::failed("scheduler has not run yet".to_string())};
+    let age = (now - last_tick).num_seconds();
+    let detail = format!("last tick {age}s ago");
+    if age <= SCHEDULER_STALE_SECS {
+        Check::ok(Some(detail))
+    } else {
+        Check::failed(detail)
+    }
+}
+
+/// トークンの確認は外部に問い合わせるので、渡されたときだけ行う
+pub async fn health_report<F>(
+    pool: &SqlitePool,
+    last_tick: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+    verify_token: Option<F>,
+) -> HealthReport
+where
+    F: Future<Output = std::result::Result<(), String>>,
+{
+    let database = check_database(pool).await;
+    let scheduler = check_scheduler(last_tick, now);
+    let line_token = match verify_token {
+        Some(verify) => Some(match verify.await {
+            Ok(()) => Check::ok(None),
+            Err(e) => Check::failed(e),
+        }),
+        None => None,
+    };
+    HealthReport {
+        ok: database.ok && scheduler.ok && line_token.as_ref().is_none_or(|c| c.ok),
+        database,
+        scheduler,
+        line_token,
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct HealthQuery {
+    #[serde(default)]
+    verify_token: bool,
+}
+
+pub async fn healthz(
+    Query(query): Query<HealthQuery>,
+    request: Request<Body>,
+) -> axum::response::Response {
+    //トークンの確認はLINEのAPIを呼ぶので、管理者だけが頼めるようにする
+    if query.verify_token && !is_admin_request(&request) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let report = health_report(
+        DB.get().unwrap(),
+        last_tick(),
+        Utc::now(),
+        query.verify_token.then(verify_access_token),
+    )
+    .await;
+    if !report.ok {
+        tracing::warn!(?report, "health check failed");
+    }
+    let status = if report.ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report)).into_response()
+}
+
+#[tokio::test]
+async fn health_report_test() {
+    let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+    let now = Utc::now();
+
+    let no_verify = || None::<std::future::Ready<std::result::Result<(), String>>>;
+    let report = health_report(&pool, Some(now - Duration::seconds(5)), now, no_verify()).await;
+    assert!(report.ok);
+    assert!(report.line_token.is_none());
+
+    let report = health_report(&pool, Some(now - Duration::minutes(5)), now, no_verify()).await;
+    assert!(!report.ok);
+    assert!(!report.scheduler.ok);
+
+    let invalid = async { Err("invalid token".to_string()) };
+    let report = health_report(&pool, Some(now), now, Some(invalid)).await;
+    assert!(!report.ok);
+    assert_eq!(report.line_token.unwrap().detail.as_deref(), Some("invalid token"));
+
+    pool.close().await;
+    let report = health_report(&pool, Some(now), now, Some(async { Ok(()) })).await;
+    assert!(!report.database.ok);
+}
@@ -0,0 +1,146 @@
+use super::*;
+
+//締め切りの何時間前からのキャンセルを直前キャンセルとして扱うか
+const LATE_CANCEL_HOURS: i64 = 24;
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct VoteRecord {
+    pub user_id: String,
+    pub previous_status: Option<String>,
+    pub status: String,
+    pub voted_at: DateTime<Utc>,
+}
+
+pub async fn record_vote(
+    attendance_id: &str,
+    user_id: &str,
+    previous_status: Option<&str>,
+    status: &str,
+) -> Result<()> {
+    sqlx::query("insert into vote_history(attendance_id,user_id,previous_status,status,voted_at) values(?,?,?,?,?)")
+        .bind(attendance_id)
+        .bind(user_id)
+        .bind(previous_status)
+        .bind(status)
+        .bind(Utc::now())
+        .execute(DB.get().unwrap())
+        .await?;
+    Ok(())
+}
+
+pub async fn get_vote_history(attendance_id: &str) -> Vec<VoteRecord> {
+    sqlx::query_as(
+        "select user_id,previous_status,status,voted_at from vote_history where attendance_id = ? order by rowid",
+    )
+    .bind(attendance_id)
+    .fetch_all(DB.get().unwrap())
+    .await
+    .unwrap_or_default()
+}
+
+/// 締め切り直前に出席から抜けて、そのまま戻ってこなかった投票
+pub async fn get_late_cancellations(attendance_id: &str) -> Vec<VoteRecord> {
+    let Ok(finishing_time) = sqlx::query_scalar::<_, DateTime<Utc>>(
+        "select finishing_schedule from attendances where attendance_id = ?",
+    )
+    .bind(attendance_id)
+    .fetch_one(DB.get().unwrap())
+    .await else {return vec![]};
+    let since = finishing_time - Duration::hours(LATE_CANCEL_HOURS);
+
+    let history = get_vote_history(attendance_id).await;
+    let current = get_attendance_status(attendance_id).await.attend;
+    late_cancellations(history, since, &current)
+}
+
+//出席から抜けたのが何度あっても、一人につき最後の一回だけを数える
+fn late_cancellations(
+    history: Vec<VoteRecord>,
+    since: DateTime<Utc>,
+    current: &[String],
+) -> Vec<VoteRecord> {
+    let mut seen = std::collections::HashSet::new();
+    let mut cancellations: Vec<VoteRecord> = history
+        .into_iter()
+        .rev()
+        .filter(|record| {
+            record.previous_status.as_deref() == Some("attend")
+                && record.status != "waiting"
+                && record.voted_at >= since
+                && !current.contains(&record.user_id)
+        })
+        .filter(|record| seen.insert(record.user_id.clone()))
+        .collect();
+    cancellations.reverse();
+    cancellations
+}
+
+pub async fn post_late_cancellations(attendance_id: &str) {
+    let cancellations = get_late_cancellations(attendance_id).await;
+    if cancellations.is_empty() {
+        return;
+    }
+    let group_id = get_group_id(attendance_id).await.unwrap_or_default();
+    let mut text = "直前キャンセル".to_string();
+    for record in cancellations {
+        text += &format!(
+            "\n{} ({} {}→{})",
+            get_display_name(&record.user_id, &group_id).await,
+            record.voted_at.with_timezone(&*TIMEZONE).format("%m/%d %H:%M"),
+            status_to_jp("attend"),
+            status_to_jp(&record.status)
+        );
+    }
+    let message = PushMessage {
+        to: group_id,
+        messages: vec![Box::new(SimpleMessage::new(&text))],
+    };
+    message.send().await;
+}
+
+#[derive(serde::Serialize)]
+pub struct HistoryView {
+    pub voted_at: String,
+    pub name: String,
+    pub previous_status: &'static str,
+    pub status: &'static str,
+}
+
+pub async fn history_view(attendance_id: &str, group_id: &str) -> Vec<HistoryView> {
+    let mut views = vec![];
+    for record in get_vote_history(attendance_id).await {
+        views.push(HistoryView {
+            voted_at: record
+                .voted_at
+                .with_timezone(&*TIMEZONE)
+                .format("%m/%d %H:%M:%S")
+                .to_string(),
+            name: get_display_name(&record.user_id, group_id).await,
+            previous_status: record.previous_status.as_deref().map_or("-", status_to_jp),
+            status: status_to_jp(&record.status),
+        });
+    }
+    views
+}
+
+#[test]
+fn late_cancellations_test() {
+    let now = Utc::now();
+    let record = |user_id: &str, previous: &str, status: &str, minutes: i64| VoteRecord {
+        user_id: user_id.to_string(),
+        previous_status: Some(previous.to_string()),
+        status: status.to_string(),
+        voted_at: now - Duration::minutes(minutes),
+    };
+    let history = vec![
+        record("U1", "attend", "holding", 50),
+        record("U1", "holding", "attend", 40),
+        record("U1", "attend", "absent", 30),
+        record("U2", "attend", "absent", 20),
+        record("U2", "absent", "attend", 10),
+    ];
+    let cancellations = late_cancellations(history, now - Duration::hours(1), &["U2".to_string()]);
+    assert_eq!(cancellations.len(), 1);
+    assert_eq!(cancellations[0].user_id, "U1");
+    assert_eq!(cancellations[0].status, "absent");
+}
@@ -36,42 +36,89 @@ async fn send_bloadcast_test() {
     message.send().await;
 }
 
+/// チャネルアクセストークンがまだ有効か確かめる
+#[tracing::instrument]
+pub async fn verify_access_token() -> std::result::Result<(), String> {
+    let url = "https://api.line.me/v2/oauth/verify";
+    let client = reqwest::Client::new();
+    let result = client
+        .post(url)
+        .form(&[("access_token", SETTINGS.TOKEN.as_str())])
+        .send()
+        .await;
+    log_response(url, &result);
+    let response = result.map_err(|e| e.to_string())?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("token verification returned {}", response.status()))
+    }
+}
+
 #[derive(Serialize)]
 pub struct PushMessage {
     pub to: String,
     pub messages: Vec<Box<dyn Message>>,
 }
 impl PushMessage {
+    #[tracing::instrument(skip_all, fields(to = %self.to, messages = self.messages.len()))]
     pub async fn send(&self) {
-        println!("{}", serde_json::to_string(self).unwrap());
-        let responce = send_post_request(
+        let _ = send_post_request(
             "https://api.line.me/v2/bot/message/push",
             &serde_json::to_string(self).unwrap(),
         )
         .await;
-        println!("{:?}", responce);
     }
 }
 
 
-async fn send_get_request(url: &str) -> Result<reqwest::Response> {
+//ログにはホストとクエリを除いたパスだけを残す (クエリにトークンが入ることがある)
+fn endpoint(url: &str) -> &str {
+    let path = url.strip_prefix("https://api.line.me").unwrap_or(url);
+    path.split('?').next().unwrap_or_default()
+}
+
+fn log_response(url: &str, result: &reqwest::Result<reqwest::Response>) {
+    let status = match result {
+        Ok(response) => response.status().as_u16().to_string(),
+        Err(_) => "error".to_string(),
+    };
+    LINE_API_CALLS
+        .with_label_values(&[&endpoint_label(url), &status])
+        .inc();
+    match result {
+        Ok(response) if response.status().is_success() => {
+            tracing::debug!(status = response.status().as_u16(), "LINE API call")
+        }
+        Ok(response) => tracing::warn!(status = response.status().as_u16(), "LINE API call failed"),
+        Err(e) => tracing::warn!(error = %e, "LINE API request failed"),
+    }
+}
+
+#[tracing::instrument(fields(endpoint = endpoint(url)), skip_all)]
+async fn send_get_request(url: &str) -> reqwest::Result<reqwest::Response> {
     let client = reqwest::Client::new();
-    Ok(client
+    let result = client
         .get(url)
         .bearer_auth(SETTINGS.TOKEN.to_string())
         .send()
-        .await?)
+        .await;
+    log_response(url, &result);
+    result
 }
 
+#[tracing::instrument(fields(endpoint = endpoint(url)), skip_all)]
 async fn send_post_request(url: &str, body: &str) -> Result<reqwest::Response> {
     let client = reqwest::Client::new();
-    Ok(client
+    let result = client
         .post(url)
         .header("Content-Type", "application/json")
         .bearer_auth(SETTINGS.TOKEN.to_string())
         .body(body.to_string())
         .send()
-        .await?)
+        .await;
+    log_response(url, &result);
+    Ok(result?)
 }
 
 
@@ -12,26 +12,83 @@ pub struct UserProfile {
     #[serde(default)]
     pub statusMessage: Option<String>,
 }
-pub async fn get_user_profile_from_friend(user_id: String) -> Option<UserProfile> {
-    let resp = send_get_request(&format!("https://api.line.me/v2/bot/profile/{user_id}"))
-        .await
-        .unwrap();
-    if resp.status() != 200 {
-        return None;
+
+#[derive(Debug)]
+pub enum ProfileError {
+    //友だちでない、グループにいないなど
+    NotFound,
+    Status(reqwest::StatusCode),
+    Request(reqwest::Error),
+    Parse(serde_json::Error),
+}
+impl std::fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProfileError::NotFound => write!(f, "profile not found"),
+            ProfileError::Status(status) => write!(f, "unexpected status: {status}"),
+            ProfileError::Request(e) => write!(f, "request failed: {e}"),
+            ProfileError::Parse(e) => write!(f, "invalid profile: {e}"),
+        }
+    }
+}
+impl std::error::Error for ProfileError {}
+impl From<reqwest::Error> for ProfileError {
+    fn from(e: reqwest::Error) -> Self {
+        ProfileError::Request(e)
+    }
+}
+impl From<serde_json::Error> for ProfileError {
+    fn from(e: serde_json::Error) -> Self {
+        ProfileError::Parse(e)
     }
-    let profile: UserProfile = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
-    Some(profile)
 }
 
-pub async fn get_user_profile_from_group(user_id: String, group_id: String) -> Option<UserProfile> {
-    let resp = send_get_request(&format!(
+/// LINEのユーザーIDの形 (U + 16進数32桁) か。APIのパスに埋め込む前に確かめる
+pub fn is_valid_user_id(user_id: &str) -> bool {
+    user_id.len() == 33
+        && user_id.starts_with('U')
+        && user_id[1..]
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+async fn get_profile(url: &str) -> std::result::Result<UserProfile, ProfileError> {
+    let resp = send_get_request(url).await?;
+    match resp.status() {
+        reqwest::StatusCode::OK => (),
+        reqwest::StatusCode::NOT_FOUND => return Err(ProfileError::NotFound),
+        status => return Err(ProfileError::Status(status)),
+    }
+    Ok(serde_json::from_str(&resp.text().await?)?)
+}
+
+pub async fn get_user_profile_from_friend(
+    user_id: String,
+) -> std::result::Result<UserProfile, ProfileError> {
+    if !is_valid_user_id(&user_id) {
+        return Err(ProfileError::NotFound);
+    }
+    get_profile(&format!("https://api.line.me/v2/bot/profile/{user_id}")).await
+}
+
+pub async fn get_user_profile_from_group(
+    user_id: String,
+    group_id: String,
+) -> std::result::Result<UserProfile, ProfileError> {
+    if !is_valid_user_id(&user_id) {
+        return Err(ProfileError::NotFound);
+    }
+    get_profile(&format!(
         "https://api.line.me/v2/bot/group/{group_id}/member/{user_id}"
     ))
     .await
-    .unwrap();
-    if resp.status() != 200 {
-        return None;
-    }
-    let profile: UserProfile = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
-    Some(profile)
-}
\ No newline at end of file
+}
+
+#[test]
+fn is_valid_user_id_test() {
+    assert!(is_valid_user_id("U4af4980629a0d1f1b4a6c3a8e1f0d1e2"));
+    assert!(!is_valid_user_id("U4AF4980629A0D1F1B4A6C3A8E1F0D1E2"));
+    assert!(!is_valid_user_id("U4af4980629a0d1f1b4a6c3a8e1f0d1e"));
+    assert!(!is_valid_user_id("../../bot/info"));
+    assert!(!is_valid_user_id("Uあいうえおかきくけこさしすせそたちつてとなにぬねのはひふへほまみむめ"));
+}
@@ -0,0 +1,52 @@
+use super::*;
+use tracing_subscriber::EnvFilter;
+
+//sqlxは全てのクエリを出力するので、普段は遅いものと失敗だけにする
+const DEFAULT_LOG_FILTER: &str = "info,sqlx=warn";
+
+/// 出力形式とレベルは設定で、さらにRUST_LOGで細かく絞り込める
+pub fn initialize_logging(settings: &Settings) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        EnvFilter::new(settings.LOG_LEVEL.as_deref().unwrap_or(DEFAULT_LOG_FILTER))
+    });
+    //標準出力はコマンドの出力に使うので、ログは標準エラーに出す
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    let result = match settings.LOG_FORMAT {
+        LogFormat::Pretty => builder.try_init(),
+        LogFormat::Json => builder.json().flatten_event(true).try_init(),
+    };
+    if let Err(e) = result {
+        eprintln!("failed to initialize logging: {e}");
+    }
+}
+
+/// メンバーのメッセージなどを残さないように、長さだけにする
+pub fn redact(text: &str) -> String {
+    format!("<redacted {} chars>", text.chars().count())
+}
+
+/// webhookのイベントからメッセージ本文と返信用トークンを取り除く
+pub fn redact_event(event: &Value) -> Value {
+    let mut event = event.clone();
+    if let Some(token) = event.get_mut("replyToken") {
+        *token = Value::String(redact(token.as_str().unwrap_or_default()));
+    }
+    if let Some(text) = event.get_mut("message").and_then(|m| m.get_mut("text")) {
+        *text = Value::String(redact(text.as_str().unwrap_or_default()));
+    }
+    event
+}
+
+#[test]
+fn redact_event_test() {
+    let event: Value = serde_json::from_str(
+        r#"{"type":"message","replyToken":"abcdef","message":{"type":"text","text":"こんにちは"}}"#,
+    )
+    .unwrap();
+    let redacted = redact_event(&event);
+    assert_eq!(redacted["replyToken"], "<redacted 6 chars>");
+    assert_eq!(redacted["message"]["text"], "<redacted 5 chars>");
+    assert_eq!(redacted["message"]["type"], "text");
+}
@@ -1,5 +1,5 @@
 use axum::body::Bytes;
-use axum::extract::Path;
+use axum::extract::{Path, Query};
 use axum::response::Html;
 use axum::*;
 use axum_server::tls_rustls::*;
@@ -7,7 +7,7 @@ use chrono::{prelude::*, Duration, FixedOffset};
 use once_cell::sync::{Lazy, OnceCell};
 use reqwest::StatusCode;
 use serde_json::Value;
-use sqlx::{Row, Sqlite};
+use sqlx::{Row, Sqlite, SqliteConnection};
 use std::net::SocketAddr;
 use std::str::FromStr;
 use std::{fs, path::PathBuf};
@@ -19,102 +19,308 @@ pub use line::*;
 pub mod scheduler;
 pub use scheduler::*;
 
-#[allow(non_snake_case)]
-#[derive(serde::Deserialize)]
-struct Settings {
-    TOKEN: String,
-    TLS_KEY_DIR_PATH: PathBuf,
-    HOST: String,
-    LISTENING_ADDRESS: String,
-    BINDED_GROUP_ID: String,
-    DEFAULT_ICON_URL: String,
-}
+pub mod seating;
+pub use seating::*;
+
+pub mod partnership;
+pub use partnership::*;
+
+pub mod history;
+pub use history::*;
+
+pub mod quorum;
+pub use quorum::*;
+
+pub mod api;
+
+pub mod admin;
+
+pub mod export;
+pub use export::*;
+
+pub mod calendar;
+pub use calendar::*;
+
+pub mod stats;
+pub use stats::*;
+
+pub mod template;
+pub use template::*;
+
+pub mod profiles;
+pub use profiles::*;
+
+pub mod access;
+pub use access::*;
+
+pub mod group;
+pub use group::*;
 
-static SETTINGS: Lazy<Settings> =
-    Lazy::new(|| toml::from_str(&fs::read_to_string("settings.toml").unwrap()).unwrap());
+pub mod dedup;
+
+pub mod queue;
+pub use queue::*;
+
+pub mod shutdown;
+pub use shutdown::*;
+
+pub mod server;
+pub use server::*;
+
+pub mod settings;
+pub use settings::*;
+
+pub mod logging;
+pub use logging::*;
+
+pub mod metrics;
+pub use metrics::*;
+
+pub mod health;
+pub use health::*;
+
+pub mod cli;
+pub use cli::*;
+
+pub mod event_template;
+pub use event_template::*;
 
 static DB: OnceCell<sqlx::pool::Pool<Sqlite>> = OnceCell::new();
-async fn initialize_db() {
-    DB.set(sqlx::SqlitePool::connect("database.sqlite").await.unwrap())
-        .unwrap();
+async fn initialize_db() -> Result<()> {
+    //初めて動かすときはファイルごと作る
+    let options = sqlx::sqlite::SqliteConnectOptions::from_str("database.sqlite")?
+        .create_if_missing(true);
+    let pool = sqlx::SqlitePool::connect_with(options).await?;
+    DB.set(pool).map_err(|_| "database is already initialized")?;
+    migrate_db().await
+}
+
+//足りないテーブルやカラムを追加する (空のデータベースからでも作れる)
+#[tracing::instrument]
+async fn migrate_db() -> Result<()> {
+    sqlx::query(
+        "create table if not exists attendances(id int auto_increment,description string,finishing_schedule datetime,attendance_id string, group_id string)",
+    )
+    .execute(DB.get().unwrap())
+    .await?;
+    sqlx::query("create table if not exists systemdata(timestamp datetime)")
+        .execute(DB.get().unwrap())
+        .await?;
+    //スケジューラーはこの1行を読み書きする
+    sqlx::query("insert into systemdata(timestamp) select null where not exists (select 1 from systemdata)")
+        .execute(DB.get().unwrap())
+        .await?;
+    add_column_if_missing("attendances", "capacity", "int").await?;
+    add_column_if_missing("attendances", "quorum_announced", "boolean not null default 0").await?;
+    add_column_if_missing("attendances", "quorum", "int").await?;
+    sqlx::query(
+        "create table if not exists seatings(attendance_id string,table_no int,seat string,user_id string)",
+    )
+    .execute(DB.get().unwrap())
+    .await?;
+    sqlx::query("create table if not exists partnerships(user_id string,partner_id string)")
+        .execute(DB.get().unwrap())
+        .await?;
+    sqlx::query("create table if not exists partner_requests(attendance_id string,user_id string)")
+        .execute(DB.get().unwrap())
+        .await?;
+    sqlx::query(
+        "create table if not exists profiles(user_id string primary key,display_name string,picture_url string,fetched_at datetime)",
+    )
+    .execute(DB.get().unwrap())
+    .await?;
+    dedup::create_table(DB.get().unwrap()).await?;
+    sqlx::query(
+        "create table if not exists groups(group_id string primary key,active boolean,updated_at datetime)",
+    )
+    .execute(DB.get().unwrap())
+    .await?;
+    sqlx::query(
+        "create table if not exists members(group_id string,user_id string,joined_at datetime,left_at datetime,primary key(group_id,user_id))",
+    )
+    .execute(DB.get().unwrap())
+    .await?;
+    sqlx::query(
+        "create table if not exists vote_history(attendance_id string,user_id string,previous_status string,status string,voted_at datetime)",
+    )
+    .execute(DB.get().unwrap())
+    .await?;
+    Ok(())
+}
+
+async fn add_column_if_missing(table: &str, column: &str, definition: &str) -> Result<()> {
+    let columns: Vec<String> =
+        sqlx::query_scalar(&format!("select name from pragma_table_info('{table}')"))
+            .fetch_all(DB.get().unwrap())
+            .await?;
+    if !columns.iter().any(|c| c == column) {
+        sqlx::query(&format!("alter table {table} add column {column} {definition}"))
+            .execute(DB.get().unwrap())
+            .await?;
+    }
+    Ok(())
 }
 
 static TIMEZONE: Lazy<FixedOffset> = Lazy::new(|| FixedOffset::east_opt(9 * 3600).unwrap());
 
 static SCHEDULER: OnceCell<Mutex<Scheduler>> = OnceCell::new();
-async fn initialize_scheduler() {
+async fn initialize_scheduler() -> Result<()> {
+    let scheduler = Scheduler::from_file("schedule.json").await?;
     SCHEDULER
-        .set(Mutex::new(Scheduler::from_file("schedule.json").await))
-        .unwrap();
+        .set(Mutex::new(scheduler))
+        .map_err(|_| "scheduler is already initialized")?;
+    Ok(())
 }
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    initialize_db().await;
-    initialize_scheduler().await;
+    let cli = <Cli as clap::Parser>::parse();
+    //設定の誤りはリクエストを受けてからではなく起動時に知らせる
+    match initialize_settings() {
+        Ok(settings) => initialize_logging(settings),
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    }
+    run_command(cli.command.unwrap_or(Command::Serve)).await
+}
+
+async fn run_server() -> Result<()> {
+    if let Err(e) = SETTINGS.check_certificates() {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+    initialize_metrics();
+    initialize_db().await?;
+    initialize_scheduler().await?;
+    resume_quorum_checks().await;
 
     let app = Router::new()
         .route("/ping", routing::get(ping))
+        .route(
+            "/metrics",
+            routing::get(metrics_page).layer(middleware::from_fn(require_admin)),
+        )
+        .route("/healthz", routing::get(healthz))
         .route("/test", routing::post(print_request))
         .route("/line/webhook", routing::post(resieve_webhook))
-        .route("/line/result/:id", routing::get(result_page));
+        .route("/line/result/:id", routing::get(result_page))
+        .route(
+            "/line/history",
+            routing::get(history_page).layer(middleware::from_fn(require_admin)),
+        )
+        .route("/line/member/:user_id", routing::get(member_page))
+        .route(
+            "/api/export.csv",
+            routing::get(export_csv).layer(middleware::from_fn(require_export_access)),
+        )
+        .route("/calendar/:file", routing::get(calendar_feed))
+        .merge(api::router())
+        .merge(admin::router());
 
-    let rustls_config = RustlsConfig::from_pem_file(
-        SETTINGS.TLS_KEY_DIR_PATH.join("fullchain.pem"),
-        SETTINGS.TLS_KEY_DIR_PATH.join("privkey.pem"),
-    )
-    .await
-    .unwrap();
+    let handle = axum_server::Handle::new();
+    let excute_server = async {
+        let result = serve(app, handle.clone()).await;
+        //サーバーが止まったらスケジューラーも止める
+        request_shutdown();
+        result
+    };
 
-    let addr = SocketAddr::from_str(&SETTINGS.LISTENING_ADDRESS).unwrap();
-    let excute_https_server =
-        axum_server::bind_rustls(addr, rustls_config).serve(app.clone().into_make_service());
+    tokio::spawn(async {
+        wait_for_signal().await;
+        request_shutdown();
+    });
+    //新しい接続の受け付けをやめて、処理中のリクエストが終わるのを待つ
+    tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            shutdown_requested().await;
+            handle.graceful_shutdown(Some(std::time::Duration::from_secs(GRACE_PERIOD_SECS)));
+        }
+    });
 
     let shedule_check = async {
         loop {
+            //実行中のチェックは途中で止めずに最後まで終わらせる
             SCHEDULER.get().unwrap().lock().await.check().await;
-            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            tokio::select! {
+                _ = tokio::time::sleep(std::time::Duration::from_secs(5)) => {}
+                _ = shutdown_requested() => break,
+            }
         }
     };
 
-    let (result, _) = tokio::join!(excute_https_server, shedule_check);
+    let (result, _) = tokio::join!(excute_server, shedule_check);
+    tracing::info!("shutting down");
+    EVENT_QUEUE.shutdown().await;
+    SCHEDULER
+        .get()
+        .unwrap()
+        .lock()
+        .await
+        .save_shedule("schedule.json")
+        .await?;
+    DB.get().unwrap().close().await;
     result?;
 
     Ok(())
 }
 
 async fn ping() -> &'static str {
-    println!("ping!");
+    tracing::debug!("ping");
     "Hello, World!"
 }
 
 async fn print_request(body: Bytes) -> StatusCode {
-    println!("{}", String::from_utf8(body.to_vec()).unwrap());
+    //中身はメンバーのメッセージかもしれないので大きさだけ残す
+    tracing::debug!(bytes = body.len(), "test request");
     StatusCode::OK
 }
 
+#[tracing::instrument(skip_all, fields(events))]
 async fn resieve_webhook(body: Bytes) -> StatusCode {
     let body = match String::from_utf8(body.to_vec()) {
         Ok(x) => x,
         Err(_) => return StatusCode::BAD_REQUEST,
     };
-    println!("{}", body);
     let json: Value = match serde_json::from_str(&body) {
         Ok(x) => x,
         Err(_) => return StatusCode::BAD_REQUEST,
     };
 
-    let event = match json.get("events") {
-        Some(e) => match e.get(0) {
-            Some(e) => e,
-            None => return StatusCode::BAD_REQUEST,
-        },
-        None => return StatusCode::BAD_REQUEST,
-    };
+    let Some(events) = json.get("events").and_then(|e| e.as_array()) else {return StatusCode::BAD_REQUEST};
+    tracing::Span::current().record("events", events.len());
+    //LINEにはすぐに返事をして、イベントは裏で処理する
+    for event in events {
+        tracing::debug!(event = %redact_event(event), "received webhook event");
+        if let Err(e) = EVENT_QUEUE.push(event.clone()) {
+            //再送してもらえば処理済みのものは重複チェックで飛ばされる
+            tracing::warn!(error = ?e, "failed to enqueue webhook event");
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+    }
 
+    StatusCode::OK
+}
+
+#[tracing::instrument(skip_all, fields(
+    event_type = event.get("type").and_then(|t| t.as_str()).unwrap_or_default(),
+    event_id = event.get("webhookEventId").and_then(|i| i.as_str()).unwrap_or_default(),
+))]
+async fn handle_event(event: &Value) {
+    //再送で同じコマンドが2回実行されないようにする
+    if dedup::is_duplicate(DB.get().unwrap(), event, Utc::now()).await {
+        tracing::info!("skipped duplicated webhook event");
+        return;
+    }
+    tracing::debug!("handling webhook event");
     let event_type = event.get("type").map(|f| f.as_str().unwrap_or_default());
+    WEBHOOK_EVENTS
+        .with_label_values(&[event_type_label(event_type)])
+        .inc();
     match event_type {
         Some("postback") => {
             insert_attendance(event).await;
@@ -122,43 +328,216 @@ async fn resieve_webhook(body: Bytes) -> StatusCode {
         Some("message") => {
             resieve_message(event).await;
         }
+        Some("follow") => {
+            on_follow(event).await;
+        }
+        Some("join") => {
+            on_join(event).await;
+        }
+        Some("leave") => {
+            on_leave(event).await;
+        }
+        Some("memberJoined") => {
+            on_member_joined(event).await;
+        }
+        Some("memberLeft") => {
+            on_member_left(event).await;
+        }
+        //ブロックされたらもう送れないので何もしない
+        Some("unfollow") => (),
         _ => (),
     }
+}
 
-    StatusCode::OK
+/// 投票を反映した結果
+struct VoteChange<'a> {
+    previous: Option<String>,
+    status: &'a str,
+    attend_before: i64,
+    attend_after: i64,
+    promoted: Option<String>,
 }
 
 async fn insert_attendance(event: &Value) -> Option<()> {
     let data = event.get("postback")?.get("data")?.as_str()?;
     let datas: Vec<_> = data.split(',').collect();
-    let attendance_id = datas[0];
-    let status = datas[1];
+    let (Some(&attendance_id), Some(&status)) = (datas.first(), datas.get(1)) else {return None};
+    //カードのボタンから来る値だけを受け付ける (waitingは定員から決める)
+    if !["attend", "holding", "absent"].contains(&status) {
+        return None;
+    }
+    let seeking = datas.get(2) == Some(&"seek");
     let user_id = event.get("source")?.get("userId")?.as_str()?;
+    let group_id = get_group_id(attendance_id).await?;
 
-    let result = sqlx::query(&format!("select * from {attendance_id} where user_id=?"))
-        .bind(user_id)
-        .fetch_one(DB.get().unwrap())
-        .await;
-    if result.is_ok() {
-        let _ = sqlx::query(&format!(
-            "update {attendance_id} set status=? where user_id=?"
-        ))
-        .bind(status)
-        .bind(user_id)
-        .execute(DB.get().unwrap())
-        .await;
-    } else {
-        let _ = sqlx::query(&format!(
-            "insert into {attendance_id}(user_id,status) values(?,?)"
-        ))
-        .bind(user_id)
-        .bind(status)
-        .execute(DB.get().unwrap())
-        .await;
+    //定員の確認から書き込み・繰り上げまでを一つのトランザクションで行い、同時の投票で定員を超えないようにする
+    let mut conn = DB.get().unwrap().acquire().await.ok()?;
+    sqlx::query("begin immediate").execute(&mut *conn).await.ok()?;
+    let change = apply_vote(&mut conn, attendance_id, user_id, status).await;
+    let end = if change.is_some() { "commit" } else { "rollback" };
+    if sqlx::query(end).execute(&mut *conn).await.is_err() {
+        let _ = sqlx::query("rollback").execute(&mut *conn).await;
+        return None;
+    }
+    drop(conn);
+    let change = change?;
+    let status = change.status;
+
+    let _ = set_partner_seeking(attendance_id, user_id, seeking && status != "absent").await;
+    if change.previous.as_deref() == Some(status) {
+        return Some(());
+    }
+    let _ = record_vote(attendance_id, user_id, change.previous.as_deref(), status).await;
+    VOTES.with_label_values(&[status]).inc();
+
+    if status == "waiting" {
+        let message = PushMessage {
+            to: user_id.to_owned(),
+            messages: vec![Box::new(SimpleMessage::new(
+                "定員に達しているため、キャンセル待ちとして登録しました",
+            ))],
+        };
+        message.send().await;
+    }
+    if let Some(promoted) = &change.promoted {
+        let _ = record_vote(attendance_id, promoted, Some("waiting"), "attend").await;
+        announce_promotion(promoted, &group_id).await;
+    }
+
+    let quorum = get_quorum(attendance_id).await;
+    if (change.attend_before >= quorum) != (change.attend_after >= quorum) {
+        schedule_quorum_check(attendance_id);
     }
     Some(())
 }
 
+/// トランザクションの中で投票を書き込む
+async fn apply_vote<'a>(
+    conn: &mut SqliteConnection,
+    attendance_id: &str,
+    user_id: &str,
+    mut status: &'a str,
+) -> Option<VoteChange<'a>> {
+    let capacity: Option<i64> =
+        sqlx::query_scalar("select capacity from attendances where attendance_id = ?")
+            .bind(attendance_id)
+            .fetch_one(&mut *conn)
+            .await
+            .ok()?;
+    let previous: Option<String> =
+        sqlx::query_scalar(&format!("select status from {attendance_id} where user_id=?"))
+            .bind(user_id)
+            .fetch_optional(&mut *conn)
+            .await
+            .ok()?;
+    let attend_before = count_status_in(conn, attendance_id, "attend").await?;
+
+    //定員に達していたらキャンセル待ちに回す
+    if status == "attend" {
+        match previous.as_deref() {
+            Some("waiting") => status = "waiting",
+            Some("attend") => (),
+            _ => {
+                if let Some(capacity) = capacity {
+                    if attend_before >= capacity {
+                        status = "waiting";
+                    }
+                }
+            }
+        }
+    }
+    if previous.as_deref() == Some(status) {
+        return Some(VoteChange {
+            previous,
+            status,
+            attend_before,
+            attend_after: attend_before,
+            promoted: None,
+        });
+    }
+
+    //rowidを投票順として使うので、状態が変わったら入れ直す
+    sqlx::query(&format!("delete from {attendance_id} where user_id=?"))
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await
+        .ok()?;
+    sqlx::query(&format!(
+        "insert into {attendance_id}(user_id,status) values(?,?)"
+    ))
+    .bind(user_id)
+    .bind(status)
+    .execute(&mut *conn)
+    .await
+    .ok()?;
+
+    let promoted = if previous.as_deref() == Some("attend") {
+        promote_waiting(conn, attendance_id).await
+    } else {
+        None
+    };
+    let attend_after = count_status_in(conn, attendance_id, "attend").await?;
+    Some(VoteChange {
+        previous,
+        status,
+        attend_before,
+        attend_after,
+        promoted,
+    })
+}
+
+async fn count_status(attendance_id: &str, status: &str) -> Option<i64> {
+    let mut conn = DB.get().unwrap().acquire().await.ok()?;
+    count_status_in(&mut conn, attendance_id, status).await
+}
+
+async fn count_status_in(
+    conn: &mut SqliteConnection,
+    attendance_id: &str,
+    status: &str,
+) -> Option<i64> {
+    sqlx::query_scalar(&format!(
+        "select count(*) from {attendance_id} where status = ?"
+    ))
+    .bind(status)
+    .fetch_one(conn)
+    .await
+    .ok()
+}
+
+//キャンセル待ちの先頭を参加に繰り上げる
+async fn promote_waiting(conn: &mut SqliteConnection, attendance_id: &str) -> Option<String> {
+    let user_id: String = sqlx::query_scalar(&format!(
+        "select user_id from {attendance_id} where status = 'waiting' order by rowid limit 1"
+    ))
+    .fetch_optional(&mut *conn)
+    .await
+    .ok()??;
+    sqlx::query(&format!(
+        "update {attendance_id} set status='attend' where user_id=?"
+    ))
+    .bind(&user_id)
+    .execute(&mut *conn)
+    .await
+    .ok()?;
+    Some(user_id)
+}
+
+async fn announce_promotion(user_id: &str, group_id: &str) {
+    let name = get_cached_profile(user_id, group_id)
+        .await
+        .map_or("キャンセル待ちの方".to_string(), |profile| {
+            profile.display_name + "さん"
+        });
+    let message = PushMessage {
+        to: group_id.to_owned(),
+        messages: vec![Box::new(SimpleMessage::new(&format!(
+            "{name}が繰り上げで参加になりました"
+        )))],
+    };
+    message.send().await;
+}
+
 async fn resieve_message(event: &Value) -> Option<()> {
     let message: &Value = event.get("message")?;
     if message.get("type")? != "text" {
@@ -174,8 +553,21 @@ async fn resieve_message(event: &Value) -> Option<()> {
         "イベント登録" => {
             push_event(lines).await.get()
         }
+        "ペア登録" => {
+            register_partnership(event).await.get()
+        }
+        "ペア解除" => {
+            unregister_partnership(event).await.get()
+        }
+        "出欠エクスポート" => {
+            export_command(event, lines).await.get()
+        }
+        "カレンダー" => {
+            let group_id = event.get("source")?.get("groupId").and_then(|g| g.as_str()).unwrap_or(&SETTINGS.BINDED_GROUP_ID);
+            format!("https://{}/calendar/{}.ics", SETTINGS.HOST, group_id)
+        }
         "使い方" => {
-            fs::read_to_string("usage.txt").unwrap()
+            usage_text()
         }
         _ => {
             if event.get("source")?.get("type")? == "user" {
@@ -194,6 +586,7 @@ async fn resieve_message(event: &Value) -> Option<()> {
     Some(())
 }
 
+#[derive(Debug)]
 enum Response {
     Success(String),
     DateParseError,
@@ -201,6 +594,10 @@ enum Response {
     PassedDate,
     UnvalidDate,
     EventNotFound,
+    PartnerNotFound,
+    DatabaseError,
+    PermissionDenied,
+    CapacityParseError,
 }
 impl Response {
     fn get(self) -> String {
@@ -211,6 +608,10 @@ impl Response {
             Response::PassedDate => "過去の日付です".to_owned(),
             Response::UnvalidDate => "不正な日付です".to_owned(),
             Response::EventNotFound => "イベントが見つかりません".to_owned(),
+            Response::PartnerNotFound => "ペアの相手をメンションしてください".to_owned(),
+            Response::DatabaseError => "データベースの更新に失敗しました".to_owned(),
+            Response::PermissionDenied => "このコマンドは管理者しか使えません".to_owned(),
+            Response::CapacityParseError => "定員は1以上の数字で指定してください".to_owned(),
         }
     }
 }
@@ -219,9 +620,24 @@ async fn push_exception(args: Vec<&str>) -> Response {
     let Some(&name) = args.get(1) else {return Response::NotEnoughArgment};
     let Some(&date) = args.get(2) else {return Response::NotEnoughArgment};
     let Ok(date) = NaiveDate::parse_from_str(date,"%Y/%m/%d")else {return Response::DateParseError};
-    let reason = args.get(3);
+    let reason = args.get(3).copied();
     let mut scheduler = SCHEDULER.get().unwrap().lock().await;
-    let Some(&mut Schedule {
+    let Some(schedule) = scheduler.get_mut(name) else {return Response::EventNotFound};
+    if let Err(response) = add_exception(schedule, date, reason) {
+        return response;
+    }
+
+    scheduler.save_shedule("schedule.json").await.unwrap();
+    Response::Success("休み登録成功".to_owned())
+}
+
+/// 毎週のスケジュールに休みの回を追加する。理由があれば当日にそれを送る
+fn add_exception(
+    schedule: &mut Schedule,
+    date: NaiveDate,
+    reason: Option<&str>,
+) -> std::result::Result<(), Response> {
+    let Schedule {
         schedule_type:
             ScheduleType::Weekly {
                 weekday,
@@ -229,10 +645,10 @@ async fn push_exception(args: Vec<&str>) -> Response {
                 ref mut exception,
             },
         ..
-    }) = scheduler.get_mut(name) else {return Response::EventNotFound};
+    } = *schedule else {return Err(Response::EventNotFound)};
 
     if weekday != date.weekday() {
-        return Response::UnvalidDate;
+        return Err(Response::UnvalidDate);
     }
     let datetime = {
         let local = NaiveDateTime::new(date, time)
@@ -241,7 +657,7 @@ async fn push_exception(args: Vec<&str>) -> Response {
         DateTime::<Utc>::from_utc(local.naive_utc(), Utc)
     };
     if datetime < Utc::now() {
-        return Response::PassedDate;
+        return Err(Response::PassedDate);
     }
     let todo = match reason {
         Some(o) => Todo::SendMessage {
@@ -253,17 +669,24 @@ async fn push_exception(args: Vec<&str>) -> Response {
         id: "休み".to_string(),
         schedule_type: ScheduleType::OneTime { datetime },
         todo,
+        paused: false,
     };
     exception.push(temp);
-
-    scheduler.save_shedule("schedule.json").await.unwrap();
-    Response::Success("休み登録成功".to_owned())
+    Ok(())
 }
 
 async fn push_event(args: Vec<&str>) -> Response {
     let Some(&name) = args.get(1) else {return Response::NotEnoughArgment};
     let Some(&date) = args.get(2) else {return Response::NotEnoughArgment};
     let duration_hour:Option<i64> = args.get(3).map(|x|x.parse().ok()).unwrap_or_default();
+    //送信時間の行を空けておけば、定員だけを指定できる
+    let capacity:Option<u32> = match args.get(4).map(|x|x.trim()).filter(|x|!x.is_empty()) {
+        None => None,
+        Some(x) => match x.parse() {
+            Ok(capacity) if capacity > 0 => Some(capacity),
+            _ => return Response::CapacityParseError,
+        },
+    };
     let Ok(date) = NaiveDateTime::parse_from_str(date,"%Y/%m/%d %H:%M") else {return Response::DateParseError};
     let date = date.and_local_timezone(*TIMEZONE).unwrap();
 
@@ -280,7 +703,8 @@ async fn push_event(args: Vec<&str>) -> Response {
                     DateTime::<Utc>::from_utc(send.naive_utc(), Utc)
                 },
             },
-            todo: Todo::CreateAttendanceCheck { hour: hour },
+            todo: Todo::CreateAttendanceCheck { hour: Some(hour), capacity, template: None },
+            paused: false,
         };
         scheduler.push(schedule).await;
         scheduler.save_shedule("schedule.json").await.unwrap();
@@ -289,18 +713,27 @@ async fn push_event(args: Vec<&str>) -> Response {
         if date < Utc::now() {
             return Response::PassedDate;
         }
-        create_attendance_check(DateTime::<Utc>::from_utc(date.naive_utc(), Utc),name).await;
+        create_attendance_check(DateTime::<Utc>::from_utc(date.naive_utc(), Utc),name,capacity,&EventTemplate::default()).await;
         Response::Success("イベントを送信しました".to_string())
     }
 }
 
+async fn get_group_id(attendance_id: &str) -> Option<String> {
+    sqlx::query_scalar("select group_id from attendances where attendance_id = ?")
+        .bind(attendance_id)
+        .fetch_one(DB.get().unwrap())
+        .await
+        .ok()
+}
+
 struct Attendance {
     attend: Vec<String>,
     holding: Vec<String>,
     absent: Vec<String>,
+    waiting: Vec<String>,
 }
 async fn get_attendance_status(attendance_id: &str) -> Attendance {
-    let query = &format!("select * from {attendance_id} where status = ?");
+    let query = &format!("select * from {attendance_id} where status = ? order by rowid");
     let attend: Vec<String> = sqlx::query_scalar(query)
         .bind("attend")
         .fetch_all(DB.get().unwrap())
@@ -316,14 +749,58 @@ async fn get_attendance_status(attendance_id: &str) -> Attendance {
         .fetch_all(DB.get().unwrap())
         .await
         .unwrap();
+    let waiting: Vec<String> = sqlx::query_scalar(query)
+        .bind("waiting")
+        .fetch_all(DB.get().unwrap())
+        .await
+        .unwrap();
     Attendance {
         attend,
         holding,
         absent,
+        waiting,
     }
 }
 
-async fn result_page(Path(attendance_id): Path<String>) -> Html<String> {
+#[derive(serde::Deserialize)]
+struct ResultPageQuery {
+    token: Option<String>,
+}
+
+fn is_admin(key: Option<&str>) -> bool {
+    matches!((key, &SETTINGS.ADMIN_KEY), (Some(key), Some(admin_key)) if !admin_key.is_empty() && key == admin_key)
+}
+
+#[derive(serde::Serialize)]
+struct MemberView {
+    user_id: String,
+    name: Option<String>,
+    icon: String,
+    //個人ページへのリンク用 (管理者にだけ出す)
+    token: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct SeatView {
+    label: &'static str,
+    name: String,
+}
+
+async fn result_page(
+    Path(attendance_id): Path<String>,
+    Query(query): Query<ResultPageQuery>,
+    request: http::Request<body::Body>,
+) -> std::result::Result<Html<String>, StatusCode> {
+    //カードのリンクから来た人か管理者しか見られない
+    let admin = is_admin_request(&request);
+    let token_valid = query
+        .token
+        .as_deref()
+        .is_some_and(|token| verify_result_token(&attendance_id, token));
+    if !admin && !token_valid {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
     let attendance = get_attendance_status(&attendance_id);
     let attendance_data = sqlx::query("select * from attendances where attendance_id = ?")
         .bind(&attendance_id)
@@ -335,79 +812,110 @@ async fn result_page(Path(attendance_id): Path<String>) -> Html<String> {
         attend,
         holding,
         absent,
+        waiting,
     } = attendance;
 
-    let attendance_data = attendance_data.unwrap();
+    let attendance_data = attendance_data.map_err(|_| StatusCode::NOT_FOUND)?;
 
     let group_id: String = attendance_data.get("group_id");
 
     let title: String = attendance_data.get("description");
 
-    let mut html = fs::read_to_string("result_page.html").unwrap();
-    html = html.replace("%TITLE%", &title.to_string());
-    html = html.replace("%ATTEND%", &attend.len().to_string());
-    html = html.replace("%HOLDING%", &holding.len().to_string());
-    html = html.replace("%ABSENT%", &absent.len().to_string());
-
-    async fn ids_to_name(user_ids: &Vec<String>, group_id: &str) -> String {
-        let mut buf = String::default();
+    async fn ids_to_members(user_ids: &Vec<String>, group_id: &str, admin: bool) -> Vec<MemberView> {
         let mut futures = vec![];
         for user_id in user_ids {
-            futures.push(tokio::spawn(get_user_profile_from_group(
-                user_id.to_owned(),
-                group_id.to_owned(),
-            )));
+            let user_id = user_id.to_owned();
+            let group_id = group_id.to_owned();
+            futures.push(tokio::spawn(async move {
+                get_cached_profile(&user_id, &group_id).await
+            }));
         }
         let mut result = vec![];
-        for future in futures {
-            result.push(future.await.unwrap());
-        }
-        for profile in result {
-            buf += &profile.map_or("UNKNOWN_USER".to_string(), |profile| {
-                let url = profile
-                    .pictureUrl
-                    .unwrap_or_else(|| SETTINGS.DEFAULT_ICON_URL.to_string());
-                let icon = format!(r####"<img src="{url}" alt="icon" class="icon">"####);
-                format!(
-                    r##"<div class="box">{}{}</div><br>"##,
-                    icon, profile.displayName
-                )
+        for (future, user_id) in futures.into_iter().zip(user_ids) {
+            //取れなかった人だけUNKNOWN_USERにする
+            let profile = future.await.unwrap_or_default();
+            result.push(MemberView {
+                user_id: user_id.clone(),
+                icon: profile
+                    .as_ref()
+                    .and_then(|profile| profile.picture_url.clone())
+                    .unwrap_or_else(|| SETTINGS.DEFAULT_ICON_URL.to_string()),
+                name: profile.map(|profile| profile.display_name),
+                token: admin.then(|| sign_member_token(user_id, Utc::now())),
             });
         }
-        buf
+        result
     }
 
-    let attends = ids_to_name(&attend, &group_id);
-    let holdings = ids_to_name(&holding, &group_id);
-    let absents = ids_to_name(&absent, &group_id);
+    let attends = ids_to_members(&attend, &group_id, admin);
+    let holdings = ids_to_members(&holding, &group_id, admin);
+    let absents = ids_to_members(&absent, &group_id, admin);
+    let waitings = ids_to_members(&waiting, &group_id, admin);
+
+    let (attends, holdings, absents, waitings) =
+        tokio::join!(attends, holdings, absents, waitings);
 
-    let (attends, holdings, absents) = tokio::join!(attends, holdings, absents);
-    html = html.replace("%ATTENDS%", &attends);
-    html = html.replace("%HOLDINGS%", &holdings);
-    html = html.replace("%ABSENTS%", &absents);
+    let mut seating = vec![];
+    if let Some(s) = get_seating(&attendance_id).await {
+        for table in s.tables {
+            let mut seats = vec![];
+            for seat in Seat::ALL {
+                seats.push(SeatView {
+                    label: seat.jp(),
+                    name: get_display_name(table.get(seat), &group_id).await,
+                });
+            }
+            seating.push(seats);
+        }
+    }
 
-    Html::from(html)
+    //管理者には投票の履歴も表示する
+    let history = if admin {
+        history_view(&attendance_id, &group_id).await
+    } else {
+        vec![]
+    };
+
+    let html = render(
+        "result.html",
+        minijinja::context! {
+            title,
+            attends,
+            holdings,
+            absents,
+            waitings,
+            seating,
+            history,
+        },
+    )
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Html::from(html))
 }
 
-async fn create_attendance_check(finishing_time: DateTime<Utc>, event_name: &str) -> Schedule {
+async fn create_attendance_check(
+    finishing_time: DateTime<Utc>,
+    event_name: &str,
+    capacity: Option<u32>,
+    template: &EventTemplate,
+) -> Schedule {
     //ランダムid生成
     use rand::Rng;
     let attendance_id = "attendance".to_owned() + &rand::thread_rng().gen::<u64>().to_string();
 
-    let text = format!(
-        "{}/{}({}){}",
-        finishing_time.month(),
-        finishing_time.day(),
-        weekday_to_jp(finishing_time.weekday()),
-        event_name
-    );
+    let text = template.describe(event_name, &finishing_time).unwrap_or_else(|e| {
+        tracing::warn!(error = %e, "failed to render event description");
+        event_name.to_string()
+    });
+    let capacity = capacity.or(template.capacity);
 
     //sqlに登録
-    sqlx::query("insert into attendances(description,group_id,finishing_schedule,attendance_id) values(?,?,?,?)")
+    sqlx::query("insert into attendances(description,group_id,finishing_schedule,attendance_id,capacity,quorum) values(?,?,?,?,?,?)")
     .bind(&text)
     .bind(&SETTINGS.BINDED_GROUP_ID)
     .bind(finishing_time)
     .bind(&attendance_id)
+    .bind(capacity)
+    .bind(template.quorum)
     .execute(DB.get().unwrap()).await.unwrap();
 
     //出欠管理用のテーブル作成
@@ -419,14 +927,22 @@ async fn create_attendance_check(finishing_time: DateTime<Utc>, event_name: &str
     .unwrap();
 
     //メッセージ送信
-    let message = PushMessage {
-        to: SETTINGS.BINDED_GROUP_ID.clone(),
-        messages: vec![Box::new(FlexMessage::new(
-            generate_flex(&attendance_id, &text),
-            &text,
-        ))],
-    };
-    message.send().await;
+    let token = sign_result_token(&attendance_id, finishing_time);
+    //テンプレートのカードが壊れていたら、いつものカードで送る
+    let flex = generate_flex(&attendance_id, &text, &token, template)
+        .or_else(|e| {
+            tracing::error!(error = %e, "failed to generate flex message from the event template");
+            generate_flex(&attendance_id, &text, &token, &EventTemplate::default())
+        })
+        .map_err(|e| tracing::error!(error = %e, "failed to generate flex message"))
+        .ok();
+    if let Some(flex) = flex {
+        let message = PushMessage {
+            to: SETTINGS.BINDED_GROUP_ID.clone(),
+            messages: vec![Box::new(FlexMessage::new(flex, &text))],
+        };
+        message.send().await;
+    }
 
     Schedule {
         id: "".to_string(),
@@ -434,6 +950,7 @@ async fn create_attendance_check(finishing_time: DateTime<Utc>, event_name: &str
             datetime: finishing_time,
         },
         todo: Todo::SendAttendanceInfo { attendance_id },
+        paused: false,
     }
 }
 
@@ -449,12 +966,73 @@ fn weekday_to_jp(weekday: chrono::Weekday) -> String {
     }
 }
 
-fn generate_flex(id: &str, description: &str) -> serde_json::Value {
-    let mut text = fs::read_to_string("vote_flex_message.json").unwrap();
-    text = text.replace("%DESCRIPTION%", description);
-    text = text.replace("%ID%", id);
-    text = text.replace("%HOST%", &SETTINGS.HOST);
-    serde_json::from_str(&text).unwrap()
+fn status_to_jp(status: &str) -> &'static str {
+    match status {
+        "attend" => "出席",
+        "holding" => "保留",
+        "absent" => "不参加",
+        "waiting" => "キャンセル待ち",
+        _ => "不明",
+    }
+}
+
+fn generate_flex(id: &str, description: &str, token: &str, template: &EventTemplate) -> Result<serde_json::Value> {
+    let mut flex: Value = serde_json::from_str(&fs::read_to_string(template.flex_path())?)?;
+    fill_placeholders(
+        &mut flex,
+        &[
+            ("%DESCRIPTION%", description),
+            ("%ID%", id),
+            ("%TOKEN%", token),
+            ("%HOST%", &SETTINGS.HOST),
+            ("%VENUE%", template.venue.as_deref().unwrap_or_default()),
+            ("%FEE%", template.fee.as_deref().unwrap_or_default()),
+        ],
+    );
+    Ok(flex)
+}
+
+//jsonとして読んでから文字列の中だけを置き換えるので、値に"や改行があっても壊れない
+fn fill_placeholders(value: &mut Value, replacements: &[(&str, &str)]) {
+    match value {
+        Value::String(text) => {
+            for (placeholder, replacement) in replacements {
+                if text.contains(placeholder) {
+                    *text = text.replace(placeholder, replacement);
+                }
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                fill_placeholders(value, replacements);
+            }
+        }
+        Value::Object(map) => {
+            for value in map.values_mut() {
+                fill_placeholders(value, replacements);
+            }
+        }
+        _ => (),
+    }
+}
+
+#[test]
+fn fill_placeholders_test() {
+    let mut flex: Value = serde_json::from_str(
+        r#"{"text":"%DESCRIPTION%","actions":[{"uri":"https://%HOST%/line/result/%ID%"}],"size":3}"#,
+    )
+    .unwrap();
+    fill_placeholders(
+        &mut flex,
+        &[
+            ("%DESCRIPTION%", "2/20(月)\n\"四谷\"練"),
+            ("%HOST%", "example.com"),
+            ("%ID%", "attendance1"),
+        ],
+    );
+    assert_eq!(flex["text"], "2/20(月)\n\"四谷\"練");
+    assert_eq!(flex["actions"][0]["uri"], "https://example.com/line/result/attendance1");
+    assert_eq!(flex["size"], 3);
 }
 
 // #[tokio::test]
@@ -493,3 +1071,36 @@ async fn sqlite_insert_test() {
         .await
         .unwrap();
 }
+
+#[tokio::test]
+async fn apply_vote_test() {
+    use sqlx::Connection;
+    let mut conn = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+    sqlx::query("create table attendances(attendance_id string,capacity int)")
+        .execute(&mut conn)
+        .await
+        .unwrap();
+    sqlx::query("insert into attendances(attendance_id,capacity) values('a1',1)")
+        .execute(&mut conn)
+        .await
+        .unwrap();
+    sqlx::query("create table a1(user_id string,status string)")
+        .execute(&mut conn)
+        .await
+        .unwrap();
+
+    let first = apply_vote(&mut conn, "a1", "U1", "attend").await.unwrap();
+    assert_eq!(first.status, "attend");
+    let second = apply_vote(&mut conn, "a1", "U2", "attend").await.unwrap();
+    assert_eq!(second.status, "waiting");
+    let cancel = apply_vote(&mut conn, "a1", "U1", "absent").await.unwrap();
+    assert_eq!(cancel.promoted.as_deref(), Some("U2"));
+    assert_eq!((cancel.attend_before, cancel.attend_after), (1, 1));
+}
+
+#[tokio::test]
+async fn push_event_capacity_test() {
+    let args = |capacity| vec!["イベント登録", "四谷練", "2030/01/01 10:00", "", capacity];
+    assert!(matches!(push_event(args("0")).await, Response::CapacityParseError));
+    assert!(matches!(push_event(args("十人")).await, Response::CapacityParseError));
+}
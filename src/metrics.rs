@@ -0,0 +1,133 @@
+use super::*;
+use axum::http::header;
+use axum::response::IntoResponse;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(collector: T) -> T {
+    REGISTRY.register(Box::new(collector.clone())).unwrap();
+    collector
+}
+
+pub static WEBHOOK_EVENTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("webhook_events_total", "受け取ったwebhookイベントの数"),
+            &["type"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static VOTES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(IntCounterVec::new(Opts::new("votes_total", "出欠の投票の数"), &["status"]).unwrap())
+});
+
+pub static LINE_API_CALLS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("line_api_calls_total", "LINE APIの呼び出し回数"),
+            &["endpoint", "status"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static SCHEDULER_TICK_SECONDS: Lazy<Histogram> = Lazy::new(|| {
+    register(
+        Histogram::with_opts(HistogramOpts::new(
+            "scheduler_tick_seconds",
+            "スケジュールの確認1回にかかった時間",
+        ))
+        .unwrap(),
+    )
+});
+
+pub static FIRED_SCHEDULES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("fired_schedules_total", "実行されたスケジュールの数"),
+            &["todo"],
+        )
+        .unwrap(),
+    )
+});
+
+/// まだ一度も記録されていない指標も最初から出力されるように登録しておく
+pub fn initialize_metrics() {
+    Lazy::force(&WEBHOOK_EVENTS);
+    Lazy::force(&VOTES);
+    Lazy::force(&LINE_API_CALLS);
+    Lazy::force(&SCHEDULER_TICK_SECONDS);
+    Lazy::force(&FIRED_SCHEDULES);
+}
+
+/// ユーザーIDなどをまとめて、ラベルの種類が増え続けないようにする
+pub fn endpoint_label(url: &str) -> String {
+    let path = url.strip_prefix("https://api.line.me").unwrap_or(url);
+    let path = path.split('?').next().unwrap_or_default();
+    path.split('/')
+        .map(|segment| {
+            let is_id = segment.len() == 33
+                && segment.starts_with(['U', 'C', 'R'])
+                && segment[1..].chars().all(|c| c.is_ascii_hexdigit());
+            if is_id {
+                ":id"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// webhookの本文は誰でも送れるので、知っている種類以外はotherにまとめる
+pub fn event_type_label(event_type: Option<&str>) -> &'static str {
+    match event_type {
+        Some("message") => "message",
+        Some("postback") => "postback",
+        Some("follow") => "follow",
+        Some("join") => "join",
+        Some("leave") => "leave",
+        Some("memberJoined") => "memberJoined",
+        Some("memberLeft") => "memberLeft",
+        _ => "other",
+    }
+}
+
+/// 管理者だけが見られる (ルーター側でrequire_adminを掛ける)
+pub async fn metrics_page() -> axum::response::Response {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    if encoder.encode(&REGISTRY.gather(), &mut buffer).is_err() {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    ([(header::CONTENT_TYPE, encoder.format_type().to_string())], buffer).into_response()
+}
+
+#[test]
+fn endpoint_label_test() {
+    assert_eq!(
+        endpoint_label("https://api.line.me/v2/bot/group/C0123456789abcdef0123456789abcdef/member/U0123456789abcdef0123456789abcdef"),
+        "/v2/bot/group/:id/member/:id"
+    );
+    assert_eq!(
+        endpoint_label("https://api.line.me/v2/bot/message/push"),
+        "/v2/bot/message/push"
+    );
+    assert_eq!(
+        endpoint_label("https://api.line.me/oauth2/v2.1/verify?access_token=secret"),
+        "/oauth2/v2.1/verify"
+    );
+}
+
+#[test]
+fn event_type_label_test() {
+    assert_eq!(event_type_label(Some("postback")), "postback");
+    assert_eq!(event_type_label(Some("memberLeft")), "memberLeft");
+    assert_eq!(event_type_label(Some("anything-else")), "other");
+    assert_eq!(event_type_label(None), "other");
+}
@@ -0,0 +1,192 @@
+use super::*;
+use std::collections::HashSet;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Matching {
+    pub fixed: Vec<(String, String)>,
+    pub matched: Vec<(String, String)>,
+    pub unpaired: Vec<String>,
+}
+impl Matching {
+    pub fn pairs(&self) -> Vec<(String, String)> {
+        self.fixed.iter().chain(self.matched.iter()).cloned().collect()
+    }
+}
+
+/// 出席者をペアにする。
+/// 固定ペアが揃っていればそのまま組み、残りはペア募集している人を優先して投票順に組む。
+pub fn match_partners(
+    attendees: &[String],
+    partnerships: &[(String, String)],
+    seekers: &[String],
+) -> Matching {
+    let mut matching = Matching::default();
+    let mut used = HashSet::new();
+    for (a, b) in partnerships {
+        if a != b
+            && attendees.contains(a)
+            && attendees.contains(b)
+            && !used.contains(a)
+            && !used.contains(b)
+        {
+            used.insert(a.clone());
+            used.insert(b.clone());
+            matching.fixed.push((a.clone(), b.clone()));
+        }
+    }
+
+    let (mut solos, others): (Vec<&String>, Vec<&String>) = attendees
+        .iter()
+        .filter(|a| !used.contains(*a))
+        .partition(|a| seekers.contains(a));
+    solos.extend(others);
+
+    for pair in solos.chunks(2) {
+        match pair {
+            [a, b] => matching.matched.push(((*a).clone(), (*b).clone())),
+            [a] => matching.unpaired.push((*a).clone()),
+            _ => unreachable!(),
+        }
+    }
+    matching
+}
+
+pub async fn get_partnerships() -> Vec<(String, String)> {
+    sqlx::query_as("select user_id,partner_id from partnerships")
+        .fetch_all(DB.get().unwrap())
+        .await
+        .unwrap_or_default()
+}
+
+pub async fn get_partner_seekers(attendance_id: &str) -> Vec<String> {
+    sqlx::query_scalar("select user_id from partner_requests where attendance_id = ? order by rowid")
+        .bind(attendance_id)
+        .fetch_all(DB.get().unwrap())
+        .await
+        .unwrap_or_default()
+}
+
+pub async fn set_partner_seeking(attendance_id: &str, user_id: &str, seeking: bool) -> Result<()> {
+    sqlx::query("delete from partner_requests where attendance_id = ? and user_id = ?")
+        .bind(attendance_id)
+        .bind(user_id)
+        .execute(DB.get().unwrap())
+        .await?;
+    if seeking {
+        sqlx::query("insert into partner_requests(attendance_id,user_id) values(?,?)")
+            .bind(attendance_id)
+            .bind(user_id)
+            .execute(DB.get().unwrap())
+            .await?;
+    }
+    Ok(())
+}
+
+async fn remove_partnerships(user_id: &str) -> Result<u64> {
+    Ok(
+        sqlx::query("delete from partnerships where user_id = ? or partner_id = ?")
+            .bind(user_id)
+            .bind(user_id)
+            .execute(DB.get().unwrap())
+            .await?
+            .rows_affected(),
+    )
+}
+
+pub(crate) async fn register_partnership(event: &Value) -> Response {
+    let Some(user_id) = event.get("source").and_then(|s| s.get("userId")).and_then(|u| u.as_str()) else {return Response::NotEnoughArgment};
+    //相手はメンションで指定してもらう
+    let Some(partner_id) = event
+        .get("message")
+        .and_then(|m| m.get("mention"))
+        .and_then(|m| m.get("mentionees"))
+        .and_then(|m| m.get(0))
+        .and_then(|m| m.get("userId"))
+        .and_then(|u| u.as_str()) else {return Response::PartnerNotFound};
+    if user_id == partner_id {
+        return Response::PartnerNotFound;
+    }
+
+    if remove_partnerships(user_id).await.is_err() || remove_partnerships(partner_id).await.is_err() {
+        return Response::DatabaseError;
+    }
+    let result = sqlx::query("insert into partnerships(user_id,partner_id) values(?,?)")
+        .bind(user_id)
+        .bind(partner_id)
+        .execute(DB.get().unwrap())
+        .await;
+    if result.is_err() {
+        return Response::DatabaseError;
+    }
+    Response::Success("ペアを登録しました".to_string())
+}
+
+pub(crate) async fn unregister_partnership(event: &Value) -> Response {
+    let Some(user_id) = event.get("source").and_then(|s| s.get("userId")).and_then(|u| u.as_str()) else {return Response::NotEnoughArgment};
+    match remove_partnerships(user_id).await {
+        Ok(0) => Response::Success("登録されているペアはありません".to_string()),
+        Ok(_) => Response::Success("ペアを解除しました".to_string()),
+        Err(_) => Response::DatabaseError,
+    }
+}
+
+/// 出欠締め切り時にペア決めの結果をグループに送信する
+pub async fn post_matching(attendance_id: &str) -> Matching {
+    let group_id = &get_group_id(attendance_id).await.unwrap_or_default();
+    let attendance = get_attendance_status(attendance_id).await;
+    let matching = match_partners(
+        &attendance.attend,
+        &get_partnerships().await,
+        &get_partner_seekers(attendance_id).await,
+    );
+    if matching.matched.is_empty() && matching.unpaired.is_empty() {
+        return matching;
+    }
+
+    let mut text = "ペア決め".to_string();
+    for (a, b) in &matching.matched {
+        text += &format!(
+            "\n{} - {}",
+            get_display_name(a, group_id).await,
+            get_display_name(b, group_id).await
+        );
+    }
+    if !matching.unpaired.is_empty() {
+        let mut names = vec![];
+        for user_id in &matching.unpaired {
+            names.push(get_display_name(user_id, group_id).await);
+        }
+        text += &format!("\nペアが見つからなかった人: {}", names.join(", "));
+    }
+    let message = PushMessage {
+        to: group_id.to_owned(),
+        messages: vec![Box::new(SimpleMessage::new(&text))],
+    };
+    message.send().await;
+    matching
+}
+
+#[test]
+fn match_partners_test() {
+    let attendees: Vec<String> = ["a", "b", "c", "d", "e", "f", "g"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    let partnerships = vec![
+        ("a".to_string(), "c".to_string()),
+        ("b".to_string(), "absent".to_string()),
+    ];
+    let seekers = vec!["f".to_string(), "d".to_string()];
+    let matching = match_partners(&attendees, &partnerships, &seekers);
+
+    assert_eq!(matching.fixed, vec![("a".to_string(), "c".to_string())]);
+    assert_eq!(
+        matching.matched,
+        vec![
+            ("d".to_string(), "f".to_string()),
+            ("b".to_string(), "e".to_string())
+        ]
+    );
+    assert_eq!(matching.unpaired, vec!["g".to_string()]);
+    assert_eq!(matching.pairs().len(), 3);
+}
@@ -0,0 +1,94 @@
+use super::*;
+use std::collections::HashSet;
+
+//この時間より古いプロフィールは裏で取り直す
+const PROFILE_TTL_HOURS: i64 = 24;
+
+//取り直し中のユーザー（同じユーザーを何重にも取りに行かないように）
+static REFRESHING: Lazy<std::sync::Mutex<HashSet<String>>> =
+    Lazy::new(|| std::sync::Mutex::new(HashSet::new()));
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct CachedProfile {
+    pub user_id: String,
+    pub display_name: String,
+    pub picture_url: Option<String>,
+    pub fetched_at: DateTime<Utc>,
+}
+
+async fn load_profile(user_id: &str) -> Option<CachedProfile> {
+    sqlx::query_as(
+        "select user_id,display_name,picture_url,fetched_at from profiles where user_id = ?",
+    )
+    .bind(user_id)
+    .fetch_optional(DB.get().unwrap())
+    .await
+    .ok()?
+}
+
+async fn store_profile(profile: &UserProfile) -> Result<()> {
+    sqlx::query(
+        "insert into profiles(user_id,display_name,picture_url,fetched_at) values(?,?,?,?) \
+         on conflict(user_id) do update set display_name=excluded.display_name, \
+         picture_url=excluded.picture_url, fetched_at=excluded.fetched_at",
+    )
+    .bind(&profile.userId)
+    .bind(&profile.displayName)
+    .bind(&profile.pictureUrl)
+    .bind(Utc::now())
+    .execute(DB.get().unwrap())
+    .await?;
+    Ok(())
+}
+
+/// LINEから取り直してキャッシュを更新する。
+/// グループを抜けた人は取れないので、最後に分かっている名前をそのまま残す
+async fn refresh_profile(user_id: &str, group_id: &str) -> Option<CachedProfile> {
+    match get_user_profile_from_group(user_id.to_owned(), group_id.to_owned()).await {
+        Ok(profile) => {
+            let _ = store_profile(&profile).await;
+        }
+        Err(ProfileError::NotFound) => {
+            let _ = sqlx::query("update profiles set fetched_at = ? where user_id = ?")
+                .bind(Utc::now())
+                .bind(user_id)
+                .execute(DB.get().unwrap())
+                .await;
+        }
+        //一時的なエラーならキャッシュはそのままにして次の機会に取り直す
+        Err(e) => tracing::warn!(user_id, error = %e, "failed to get profile"),
+    }
+    load_profile(user_id).await
+}
+
+fn refresh_in_background(user_id: &str, group_id: &str) {
+    if !REFRESHING.lock().unwrap().insert(user_id.to_owned()) {
+        return;
+    }
+    let user_id = user_id.to_owned();
+    let group_id = group_id.to_owned();
+    tokio::spawn(async move {
+        refresh_profile(&user_id, &group_id).await;
+        REFRESHING.lock().unwrap().remove(&user_id);
+    });
+}
+
+/// キャッシュ済みのプロフィールを返す。古ければ裏で取り直し、無ければその場で取りに行く
+pub async fn get_cached_profile(user_id: &str, group_id: &str) -> Option<CachedProfile> {
+    match load_profile(user_id).await {
+        Some(profile) => {
+            if profile.fetched_at < Utc::now() - Duration::hours(PROFILE_TTL_HOURS) {
+                refresh_in_background(user_id, group_id);
+            }
+            Some(profile)
+        }
+        None => refresh_profile(user_id, group_id).await,
+    }
+}
+
+pub async fn get_display_name(user_id: &str, group_id: &str) -> String {
+    get_cached_profile(user_id, group_id)
+        .await
+        .map_or("UNKNOWN_USER".to_string(), |profile| profile.display_name)
+}
+
@@ -0,0 +1,120 @@
+use super::*;
+use std::collections::hash_map::DefaultHasher;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+//同時に処理するワーカーの数
+const WORKERS: usize = 4;
+//ワーカーごとに溜めておけるイベントの数
+const QUEUE_SIZE: usize = 64;
+
+pub static EVENT_QUEUE: Lazy<EventQueue> =
+    Lazy::new(|| EventQueue::start(|event| async move { handle_event(&event).await }));
+
+/// webhookで受け取ったイベントを裏で処理するためのキュー。
+/// 同じ送信元のイベントは必ず同じワーカーに回して、届いた順に処理する
+pub struct EventQueue {
+    senders: std::sync::Mutex<Option<Vec<mpsc::Sender<Value>>>>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+#[derive(Debug)]
+pub enum EnqueueError {
+    Full,
+    Closed,
+}
+
+impl EventQueue {
+    fn start<F, Fut>(handler: F) -> Self
+    where
+        F: Fn(Value) -> Fut + Clone + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let mut senders = vec![];
+        let mut workers = vec![];
+        for _ in 0..WORKERS {
+            let (sender, mut receiver) = mpsc::channel::<Value>(QUEUE_SIZE);
+            senders.push(sender);
+            let handler = handler.clone();
+            workers.push(tokio::spawn(async move {
+                while let Some(event) = receiver.recv().await {
+                    //1つのイベントの処理でパニックしても、ワーカーは止めずに次のイベントに進む
+                    if let Err(e) = tokio::spawn(handler(event)).await {
+                        tracing::error!(error = %e, "webhook event handler panicked");
+                    }
+                }
+            }));
+        }
+        EventQueue {
+            senders: std::sync::Mutex::new(Some(senders)),
+            workers: Mutex::new(workers),
+        }
+    }
+
+    pub fn push(&self, event: Value) -> std::result::Result<(), EnqueueError> {
+        let senders = self.senders.lock().unwrap();
+        let Some(senders) = senders.as_ref() else {return Err(EnqueueError::Closed)};
+        let sender = &senders[shard(&event, senders.len())];
+        sender.try_send(event).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => EnqueueError::Full,
+            mpsc::error::TrySendError::Closed(_) => EnqueueError::Closed,
+        })
+    }
+
+    /// 新しいイベントの受け付けをやめて、溜まっているイベントを全て処理し終わるまで待つ
+    pub async fn shutdown(&self) {
+        self.senders.lock().unwrap().take();
+        let workers: Vec<_> = self.workers.lock().await.drain(..).collect();
+        for worker in workers {
+            let _ = worker.await;
+        }
+    }
+}
+
+fn shard(event: &Value, count: usize) -> usize {
+    let source = event.get("source");
+    let key = source
+        .and_then(|s| s.get("userId"))
+        .or_else(|| source.and_then(|s| s.get("groupId")))
+        .and_then(|k| k.as_str())
+        .unwrap_or_default();
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % count as u64) as usize
+}
+
+#[test]
+fn shard_test() {
+    let event = |user_id: &str| -> Value {
+        serde_json::from_str(&format!(r#"{{"source":{{"type":"user","userId":"{user_id}"}}}}"#))
+            .unwrap()
+    };
+    assert_eq!(shard(&event("U1"), WORKERS), shard(&event("U1"), WORKERS));
+    assert!((0..100).all(|i| shard(&event(&format!("U{i}")), WORKERS) < WORKERS));
+}
+
+#[tokio::test]
+async fn worker_survives_panic_test() {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<String>();
+    let queue = EventQueue::start(move |event: Value| {
+        let sender = sender.clone();
+        async move {
+            if event["panic"] == true {
+                panic!("broken event");
+            }
+            sender.send(event["id"].as_str().unwrap().to_string()).unwrap();
+        }
+    });
+    let event = |id: &str, panic: bool| -> Value {
+        serde_json::json!({"id": id, "panic": panic, "source": {"userId": "U1"}})
+    };
+    queue.push(event("first", true)).unwrap();
+    queue.push(event("second", false)).unwrap();
+    assert_eq!(receiver.recv().await.unwrap(), "second");
+    //同じワーカーがまだ受け付けている
+    queue.push(event("third", false)).unwrap();
+    assert_eq!(receiver.recv().await.unwrap(), "third");
+    queue.shutdown().await;
+}
@@ -0,0 +1,136 @@
+use super::*;
+use std::collections::HashMap;
+
+//卓が立つのに必要な人数
+pub const QUORUM: i64 = 4;
+//投票が行ったり来たりしても連投しないように、状態が落ち着くまで待つ時間
+const DEBOUNCE: std::time::Duration = std::time::Duration::from_secs(300);
+
+/// イベントのテンプレートで決められた人数。無ければQUORUM
+pub async fn get_quorum(attendance_id: &str) -> i64 {
+    sqlx::query_scalar::<_, Option<i64>>("select quorum from attendances where attendance_id = ?")
+        .bind(attendance_id)
+        .fetch_one(DB.get().unwrap())
+        .await
+        .ok()
+        .flatten()
+        .unwrap_or(QUORUM)
+}
+
+//出欠ごとの待ち合わせの世代。新しく待ち始めたら古いタスクは通知しない
+static PENDING_CHECKS: Lazy<std::sync::Mutex<PendingChecks>> =
+    Lazy::new(|| std::sync::Mutex::new(PendingChecks::default()));
+
+#[derive(Default)]
+struct PendingChecks {
+    generations: HashMap<String, u64>,
+}
+impl PendingChecks {
+    /// 待ち直しを始めて、そのタスクの世代を返す
+    fn start(&mut self, attendance_id: &str) -> u64 {
+        let generation = self.generations.entry(attendance_id.to_owned()).or_default();
+        *generation += 1;
+        *generation
+    }
+    /// 待ち終わったタスクがまだ最新なら片付けてtrueを返す
+    fn finish(&mut self, attendance_id: &str, generation: u64) -> bool {
+        if self.generations.get(attendance_id) != Some(&generation) {
+            return false;
+        }
+        self.generations.remove(attendance_id);
+        true
+    }
+}
+
+/// 出席人数が卓の成立ラインをまたいだときに呼ぶ。
+/// 最後にまたいでからしばらく待って、前回通知した状態と違っていればグループに通知する。
+pub fn schedule_quorum_check(attendance_id: &str) {
+    let attendance_id = attendance_id.to_owned();
+    let generation = PENDING_CHECKS.lock().unwrap().start(&attendance_id);
+    tokio::spawn(async move {
+        tokio::time::sleep(DEBOUNCE).await;
+        if PENDING_CHECKS.lock().unwrap().finish(&attendance_id, generation) {
+            notify_quorum_change(&attendance_id).await;
+        }
+    });
+}
+
+/// 待っている間に止まると通知が失われるので、起動時に締め切り前の出欠をすべて確認し直す
+pub async fn resume_quorum_checks() {
+    let attendance_ids: Vec<String> =
+        sqlx::query_scalar("select attendance_id from attendances where finishing_schedule > ?")
+            .bind(Utc::now())
+            .fetch_all(DB.get().unwrap())
+            .await
+            .unwrap_or_default();
+    for attendance_id in attendance_ids {
+        schedule_quorum_check(&attendance_id);
+    }
+}
+
+//成立ラインの状態が前回の通知と変わっていれば、新しい状態を返す
+fn quorum_change(attend: i64, quorum: i64, announced: bool) -> Option<bool> {
+    let reached = attend >= quorum;
+    (reached != announced).then_some(reached)
+}
+
+async fn notify_quorum_change(attendance_id: &str) -> Option<()> {
+    let (group_id, finishing_time, announced): (String, DateTime<Utc>, bool) = sqlx::query_as(
+        "select group_id,finishing_schedule,quorum_announced from attendances where attendance_id = ?",
+    )
+    .bind(attendance_id)
+    .fetch_one(DB.get().unwrap())
+    .await
+    .ok()?;
+    if finishing_time < Utc::now() {
+        return None;
+    }
+
+    let attend = count_status(attendance_id, "attend").await?;
+    let reached = quorum_change(attend, get_quorum(attendance_id).await, announced)?;
+    sqlx::query("update attendances set quorum_announced = ? where attendance_id = ?")
+        .bind(reached)
+        .bind(attendance_id)
+        .execute(DB.get().unwrap())
+        .await
+        .ok()?;
+
+    let description: String =
+        sqlx::query_scalar("select description from attendances where attendance_id = ?")
+            .bind(attendance_id)
+            .fetch_one(DB.get().unwrap())
+            .await
+            .ok()?;
+    let text = if reached {
+        format!("{description}\n卓が立ちました！（出席{attend}人）")
+    } else {
+        format!("{description}\n卓が崩れました…（出席{attend}人）")
+    };
+    let message = PushMessage {
+        to: group_id,
+        messages: vec![Box::new(SimpleMessage::new(&text))],
+    };
+    message.send().await;
+    Some(())
+}
+
+#[test]
+fn pending_checks_test() {
+    let mut pending = PendingChecks::default();
+    let first = pending.start("a1");
+    let second = pending.start("a1");
+    let other = pending.start("a2");
+    //後から待ち始めたタスクだけが通知する
+    assert!(!pending.finish("a1", first));
+    assert!(pending.finish("a1", second));
+    assert!(!pending.finish("a1", second));
+    assert!(pending.finish("a2", other));
+}
+
+#[test]
+fn quorum_change_test() {
+    assert_eq!(quorum_change(4, 4, false), Some(true));
+    assert_eq!(quorum_change(4, 4, true), None);
+    assert_eq!(quorum_change(3, 4, true), Some(false));
+    assert_eq!(quorum_change(3, 4, false), None);
+}
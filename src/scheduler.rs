@@ -3,10 +3,19 @@ use super::*;
 use chrono::Weekday;
 use serde::{Deserialize, Serialize};
 
+//スケジュールにもテンプレートにも締め切りが無いときの時間
+const DEFAULT_DEADLINE_HOURS: i64 = 6;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Todo {
     CreateAttendanceCheck {
-        hour: i64,
+        //無ければテンプレートの値を使う
+        #[serde(default)]
+        hour: Option<i64>,
+        #[serde(default)]
+        capacity: Option<u32>,
+        #[serde(default)]
+        template: Option<String>,
     },
     SendAttendanceInfo {
         attendance_id: String,
@@ -19,22 +28,43 @@ pub enum Todo {
 }
 
 impl Todo {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::CreateAttendanceCheck { .. } => "CreateAttendanceCheck",
+            Self::SendAttendanceInfo { .. } => "SendAttendanceInfo",
+            Self::Test => "Test",
+            Self::SendMessage { .. } => "SendMessage",
+            Self::Nothing => "Nothing",
+        }
+    }
+    /// 出欠確認を送ってから締め切りまでの時間。定員と同じく、スケジュールの値がテンプレートより優先する
+    pub fn deadline_hours(&self) -> Option<i64> {
+        let Self::CreateAttendanceCheck { hour, template, .. } = self else {return None};
+        Some(
+            hour.or_else(|| get_event_template(template.as_deref()).hour)
+                .unwrap_or(DEFAULT_DEADLINE_HOURS),
+        )
+    }
     async fn excute(&self, schedule_id:&str ,time:DateTime<Utc>) -> Option<Schedule> {
         match self {
-            Self::CreateAttendanceCheck { hour } => {
+            Self::CreateAttendanceCheck { capacity, template, .. } => {
+                let template = get_event_template(template.as_deref());
+                let hour = self.deadline_hours().unwrap_or_default();
                 let schedule =
-                    create_attendance_check(time + Duration::hours(*hour) ,schedule_id).await;
+                    create_attendance_check(time + Duration::hours(hour) ,schedule_id, *capacity, &template).await;
                 return Some(schedule);
             }
             Self::Test => {
-                println!("called!!!")
+                tracing::info!("test schedule called")
             }
             Self::SendAttendanceInfo {
                 attendance_id,
             } => {
+                post_late_cancellations(attendance_id).await;
+                let matching = post_matching(attendance_id).await;
                 let attendance = get_attendance_status(attendance_id).await;
-                let attend = attendance.attend.len();
-                if attend < 4 {
+                let attend = attendance.attend.len() as i64;
+                if attend < get_quorum(attendance_id).await {
                     let message = PushMessage {
                         to: SETTINGS.BINDED_GROUP_ID.clone(),
                         messages: vec![Box::new(SimpleMessage::new(
@@ -42,6 +72,8 @@ impl Todo {
                         ))],
                     };
                     message.send().await;
+                } else {
+                    assign_and_post_tables(attendance_id, &matching.pairs()).await;
                 }
             }
             Self::SendMessage {contents} =>{
@@ -94,6 +126,40 @@ impl ScheduleType {
             }
         }
     }
+    //from以降until未満で発火する時刻を全て返す
+    fn occurrences(&self, from: &DateTime<Utc>, until: &DateTime<Utc>) -> Vec<DateTime<Utc>> {
+        match self {
+            Self::OneTime { datetime } => {
+                if from <= datetime && datetime < until {
+                    vec![*datetime]
+                } else {
+                    vec![]
+                }
+            }
+            Self::Weekly { weekday, time, .. } => {
+                let local_from = from.with_timezone(&*TIMEZONE).date_naive();
+                let mut temp = weekday.num_days_from_monday() as i64
+                    - local_from.weekday().num_days_from_monday() as i64;
+                if temp < 0 {
+                    temp += 7
+                }
+                let mut date = local_from + Duration::days(temp);
+                let mut result = vec![];
+                loop {
+                    let local_datetime = NaiveDateTime::new(date, *time).and_local_timezone(*TIMEZONE).unwrap();
+                    let datetime:DateTime<Utc> = DateTime::from_utc(local_datetime.naive_utc(),Utc);
+                    if &datetime >= until {
+                        break;
+                    }
+                    if &datetime >= from {
+                        result.push(datetime);
+                    }
+                    date += Duration::days(7);
+                }
+                result
+            }
+        }
+    }
     fn delete_check(&self) -> bool {
         match self {
             Self::OneTime { .. } => true,
@@ -127,6 +193,9 @@ pub struct Schedule {
     pub id: String,
     pub todo: Todo,
     pub schedule_type: ScheduleType,
+    //管理画面から止めた予定。止めている間に時刻を過ぎた一回きりの予定は、送らずに消える
+    #[serde(default)]
+    pub paused: bool,
 }
 
 impl Schedule {
@@ -136,17 +205,26 @@ impl Schedule {
         &mut self,
         last: &DateTime<Utc>,
         now: &DateTime<Utc>,
+        suppressed: bool,
     ) -> (bool, Option<Schedule>) {
+        //止めている予定は、休みの回の理由も送らない
+        let suppressed = suppressed || self.paused;
         if let ScheduleType::Weekly {
             ref mut exception, ..
         } = self.schedule_type
         {
-            if Self::check_schedules(exception, last, now).await > 0 {
+            if Self::check_schedules(exception, last, now, suppressed).await > 0 {
                 return (true, None);
             }
         }
         let (fired,fired_time) = self.schedule_type.check(last, now);
         if fired {
+            //止めている間の回は送らずに済ませる。一回きりの予定はそのまま消える
+            if suppressed {
+                tracing::info!(schedule = %self.id, "skipped a suspended schedule");
+                return (true, None);
+            }
+            FIRED_SCHEDULES.with_label_values(&[self.todo.name()]).inc();
             if let Some(o) = self.todo.excute(&self.id,fired_time).await {
                 return (true, Some(o));
             }
@@ -158,12 +236,13 @@ impl Schedule {
         schedules: &mut Vec<Schedule>,
         last: &DateTime<Utc>,
         now: &DateTime<Utc>,
+        suppressed: bool,
     ) -> u64 {
         let mut count = 0;
         let mut index = 0;
         while index < schedules.len() {
             let item = schedules.get_mut(index).unwrap();
-            let (excuted, sch) = item.check(last, now).await;
+            let (excuted, sch) = item.check(last, now, suppressed).await;
             let delete_flag = item.schedule_type.delete_check();
             if let Some(o) = sch {
                 schedules.push(o);
@@ -185,53 +264,122 @@ impl Schedule {
 pub struct Scheduler {
     schedules: Vec<Schedule>,
     timestamp: DateTime<Utc>,
+    //ボットが紐付けられたグループから外されている間は、どの予定も送らない
+    group_inactive: bool,
 }
 
 impl Scheduler {
-    pub async fn from_file(path: &str) -> Self {
-        let schedules: Vec<Schedule> =
-            serde_json::from_reader(fs::File::open(path).unwrap()).unwrap();
+    pub async fn from_file(path: &str) -> Result<Self> {
+        //まだ一度も保存していなければ予定なしで始める
+        let schedules: Vec<Schedule> = match fs::File::open(path) {
+            Ok(file) => serde_json::from_reader(file)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
         let timestamp: DateTime<Utc> = sqlx::query("select * from systemdata")
             .fetch_one(DB.get().unwrap())
-            .await
-            .unwrap()
+            .await?
             .get::<Option<DateTime<Utc>>, _>("timestamp")
             .unwrap_or_else(Utc::now);
 
-        Scheduler {
+        let group_active: Option<bool> =
+            sqlx::query_scalar("select active from groups where group_id = ?")
+                .bind(&SETTINGS.BINDED_GROUP_ID)
+                .fetch_optional(DB.get().unwrap())
+                .await
+                .unwrap_or_default();
+
+        Ok(Scheduler {
             schedules,
             timestamp,
-        }
+            group_inactive: group_active == Some(false),
+        })
     }
     pub async fn save_shedule(&self, path: &str) -> Result<()> {
-        fs::write(path, serde_json::to_string(&self.schedules)?)?;
+        //書き込み途中で止まっても元のファイルが壊れないように、別のファイルに書いてから置き換える
+        let temporary = format!("{path}.tmp");
+        fs::write(&temporary, serde_json::to_string(&self.schedules)?)?;
+        fs::rename(&temporary, path)?;
         Ok(())
     }
+    #[tracing::instrument(name = "scheduler_tick", skip_all)]
     pub async fn check(&mut self) {
+        let _timer = SCHEDULER_TICK_SECONDS.start_timer();
         let last = self.timestamp;
         let now = Utc::now();
         let sql_result = sqlx::query("update systemdata set timestamp=?")
             .bind(now)
             .execute(DB.get().unwrap())
             .await;
-        if sql_result.is_err() {
+        if let Err(e) = sql_result {
+            tracing::error!(error = %e, "failed to update scheduler timestamp");
             return;
         }
         self.timestamp = now;
 
-        if Schedule::check_schedules(&mut self.schedules, &last, &now).await > 0 {
+        let fired =
+            Schedule::check_schedules(&mut self.schedules, &last, &now, self.group_inactive).await;
+        if fired > 0 {
+            tracing::info!(fired, "fired schedules");
             self.save_shedule("schedule.json").await.unwrap();
         }
+        record_tick(now);
     }
     pub async fn push(&mut self, schedule: Schedule) {
         self.schedules.push(schedule)
     }
+    pub fn schedules(&self) -> &[Schedule] {
+        &self.schedules
+    }
+    pub fn schedules_mut(&mut self) -> &mut Vec<Schedule> {
+        &mut self.schedules
+    }
+    pub fn remove(&mut self, name: &str) -> Option<Schedule> {
+        let index = self.schedules.iter().position(|i| i.id == name)?;
+        Some(self.schedules.remove(index))
+    }
     pub fn get(&self, name: &str) -> Option<&Schedule> {
         self.schedules.iter().find(|i| i.id == name)
     }
     pub fn get_mut(&mut self, name: &str) -> Option<&mut Schedule> {
         self.schedules.iter_mut().find(|i| i.id == name)
     }
+    pub fn set_group_inactive(&mut self, inactive: bool) {
+        self.group_inactive = inactive;
+    }
+    /// これから出欠確認が送られる予定のイベント名と開催時刻。
+    /// 毎週の予定は展開して、休み登録された回は除く
+    pub fn upcoming_events(&self, from: &DateTime<Utc>, until: &DateTime<Utc>) -> Vec<(String, DateTime<Utc>)> {
+        let mut events = vec![];
+        if self.group_inactive {
+            return events;
+        }
+        for schedule in self.schedules.iter().filter(|s| !s.paused) {
+            let Some(hour) = schedule.todo.deadline_hours() else {continue};
+            let exception: Vec<DateTime<Utc>> = match &schedule.schedule_type {
+                ScheduleType::Weekly { exception, .. } => exception
+                    .iter()
+                    .filter_map(|e| match e.schedule_type {
+                        ScheduleType::OneTime { datetime } => Some(datetime),
+                        _ => None,
+                    })
+                    .collect(),
+                ScheduleType::OneTime { .. } => vec![],
+            };
+            //出欠確認を送る時刻がfromより前でも、開催がfrom以降なら含める
+            for sending in schedule
+                .schedule_type
+                .occurrences(&(*from - Duration::hours(hour)), &(*until - Duration::hours(hour)))
+            {
+                if sending <= self.timestamp || exception.contains(&sending) {
+                    continue;
+                }
+                events.push((schedule.id.clone(), sending + Duration::hours(hour)));
+            }
+        }
+        events.sort_by_key(|(_, datetime)| *datetime);
+        events
+    }
 }
 
 #[tokio::test]
@@ -244,8 +392,11 @@ async fn scheduler_gen() {
             id: "".to_string(),
             schedule_type: mon,
             todo: Todo::CreateAttendanceCheck {
-                hour: 6,
+                hour: Some(6),
+                capacity: None,
+                template: None,
             },
+            paused: false,
         })
         .await;
     scheduler
@@ -253,8 +404,11 @@ async fn scheduler_gen() {
             id: "".to_string(),
             schedule_type: thu,
             todo: Todo::CreateAttendanceCheck {
-                hour: 6,
+                hour: Some(6),
+                capacity: None,
+                template: None,
             },
+            paused: false,
         })
         .await;
     scheduler.save_shedule("schedule.json").await.unwrap();
@@ -276,6 +430,7 @@ async fn scheduler_test() {
             id: "".to_string(),
             schedule_type: _onetime,
             todo: Todo::Test,
+            paused: false,
         })
         .await;
     let shedule_check = async {
@@ -296,9 +451,134 @@ async fn serde_test() {
             datetime: Utc::now(),
         },
         todo: Todo::CreateAttendanceCheck {
-            hour: 7,
+            hour: Some(7),
+            capacity: None,
+            template: None,
         },
+        paused: false,
     };
     scheduler.schedules.push(schedule);
     scheduler.save_shedule("schedule.json").await.unwrap();
 }
+
+
+#[test]
+fn upcoming_events_test() {
+    let time = NaiveTime::from_hms_opt(10, 0, 0).unwrap();
+    //2023/2/20は月曜日
+    let skipped = NaiveDate::from_ymd_opt(2023, 2, 27)
+        .unwrap()
+        .and_time(time)
+        .and_local_timezone(*TIMEZONE)
+        .unwrap();
+    let scheduler = Scheduler {
+        schedules: vec![
+            Schedule {
+                id: "四谷練".to_string(),
+                schedule_type: ScheduleType::Weekly {
+                    weekday: Weekday::Mon,
+                    time,
+                    exception: vec![Schedule {
+                        id: "休み".to_string(),
+                        schedule_type: ScheduleType::OneTime {
+                            datetime: skipped.with_timezone(&Utc),
+                        },
+                        todo: Todo::Nothing,
+                        paused: false,
+                    }],
+                },
+                todo: Todo::CreateAttendanceCheck {
+                    hour: Some(6),
+                    capacity: None,
+                    template: None,
+                },
+                paused: false,
+            },
+            Schedule {
+                id: "".to_string(),
+                schedule_type: ScheduleType::OneTime {
+                    datetime: skipped.with_timezone(&Utc),
+                },
+                todo: Todo::Test,
+                paused: false,
+            },
+        ],
+        timestamp: DateTime::<Utc>::MIN_UTC,
+        group_inactive: false,
+    };
+    let from = NaiveDate::from_ymd_opt(2023, 2, 20)
+        .unwrap()
+        .and_hms_opt(12, 0, 0)
+        .unwrap()
+        .and_local_timezone(*TIMEZONE)
+        .unwrap()
+        .with_timezone(&Utc);
+    let events = scheduler.upcoming_events(&from, &(from + Duration::weeks(3)));
+    let days: Vec<u32> = events
+        .iter()
+        .map(|(_, datetime)| datetime.with_timezone(&*TIMEZONE).day())
+        .collect();
+    assert_eq!(days, vec![20, 6]);
+    assert!(events.iter().all(|(id, datetime)| id == "四谷練"
+        && datetime.with_timezone(&*TIMEZONE).hour() == 16));
+}
+
+#[tokio::test]
+async fn paused_schedule_test() {
+    let now = Utc::now();
+    let last = now - Duration::seconds(10);
+    let fired_at = now - Duration::seconds(5);
+    let one_time = |id: &str, paused: bool| Schedule {
+        id: id.to_string(),
+        schedule_type: ScheduleType::OneTime { datetime: fired_at },
+        todo: Todo::Nothing,
+        paused,
+    };
+
+    //止めた一回きりの予定は、時刻を過ぎたら送らずに消える
+    let mut schedules = vec![one_time("停止", true), one_time("通常", false)];
+    assert_eq!(Schedule::check_schedules(&mut schedules, &last, &now, false).await, 2);
+    assert!(schedules.is_empty());
+
+    //グループから外されている間の回も送らずに消える
+    let mut schedules = vec![one_time("通常", false)];
+    assert_eq!(Schedule::check_schedules(&mut schedules, &last, &now, true).await, 1);
+    assert!(schedules.is_empty());
+
+    //止めた毎週の予定では、休みの理由も送らない
+    let sent = || FIRED_SCHEDULES.with_label_values(&["SendMessage"]).get();
+    let before = sent();
+    let mut schedules = vec![Schedule {
+        id: "四谷練".to_string(),
+        schedule_type: ScheduleType::Weekly {
+            weekday: Weekday::Mon,
+            time: NaiveTime::from_hms_opt(10, 0, 0).unwrap(),
+            exception: vec![Schedule {
+                id: "休み".to_string(),
+                schedule_type: ScheduleType::OneTime { datetime: fired_at },
+                todo: Todo::SendMessage {
+                    contents: SimpleMessage::new("お休みです"),
+                },
+                paused: false,
+            }],
+        },
+        todo: Todo::Nothing,
+        paused: true,
+    }];
+    Schedule::check_schedules(&mut schedules, &last, &now, false).await;
+    assert_eq!(sent(), before);
+    let ScheduleType::Weekly { exception, .. } = &schedules[0].schedule_type else {panic!()};
+    assert!(exception.is_empty());
+}
+
+#[test]
+fn deadline_hours_test() {
+    let todo = |hour| Todo::CreateAttendanceCheck {
+        hour,
+        capacity: None,
+        template: None,
+    };
+    assert_eq!(todo(Some(3)).deadline_hours(), Some(3));
+    assert_eq!(todo(None).deadline_hours(), Some(DEFAULT_DEADLINE_HOURS));
+    assert_eq!(Todo::Nothing.deadline_hours(), None);
+}
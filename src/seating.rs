@@ -0,0 +1,375 @@
+use super::*;
+use rand::seq::SliceRandom;
+use std::collections::HashSet;
+
+//前回の対戦相手を避けるために試す並べ方の数
+const SHUFFLE_TRIALS: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Seat {
+    North,
+    East,
+    South,
+    West,
+}
+impl Seat {
+    pub const ALL: [Seat; 4] = [Seat::North, Seat::East, Seat::South, Seat::West];
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Seat::North => "N",
+            Seat::East => "E",
+            Seat::South => "S",
+            Seat::West => "W",
+        }
+    }
+    pub fn from_code(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|seat| seat.as_str() == s)
+    }
+    pub fn jp(&self) -> &'static str {
+        match self {
+            Seat::North => "北",
+            Seat::East => "東",
+            Seat::South => "南",
+            Seat::West => "西",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Table {
+    pub north: String,
+    pub east: String,
+    pub south: String,
+    pub west: String,
+}
+impl Table {
+    pub fn get(&self, seat: Seat) -> &String {
+        match seat {
+            Seat::North => &self.north,
+            Seat::East => &self.east,
+            Seat::South => &self.south,
+            Seat::West => &self.west,
+        }
+    }
+    fn opponents(&self) -> [(&String, &String); 4] {
+        [
+            (&self.north, &self.east),
+            (&self.north, &self.west),
+            (&self.south, &self.east),
+            (&self.south, &self.west),
+        ]
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Seating {
+    pub tables: Vec<Table>,
+    pub sitting_out: Vec<String>,
+}
+
+//順番に関係なく比較できるように並べ替えたペア
+fn pair_key(a: &str, b: &str) -> (String, String) {
+    if a < b {
+        (a.to_owned(), b.to_owned())
+    } else {
+        (b.to_owned(), a.to_owned())
+    }
+}
+
+impl Seating {
+    pub fn opponent_pairs(&self) -> HashSet<(String, String)> {
+        self.tables
+            .iter()
+            .flat_map(|table| table.opponents())
+            .map(|(a, b)| pair_key(a, b))
+            .collect()
+    }
+}
+
+/// 参加者を4人ずつの卓に分ける。
+/// 固定ペアは必ず南北か東西で組ませ、前回の対戦相手とはなるべく当たらないようにする。
+pub fn assign_tables(
+    players: &[String],
+    partnerships: &[(String, String)],
+    last_opponents: &HashSet<(String, String)>,
+) -> Seating {
+    let mut rng = rand::thread_rng();
+
+    let mut used = HashSet::new();
+    let mut fixed_pairs = vec![];
+    for (a, b) in partnerships {
+        if a != b
+            && players.contains(a)
+            && players.contains(b)
+            && !used.contains(a)
+            && !used.contains(b)
+        {
+            used.insert(a.clone());
+            used.insert(b.clone());
+            fixed_pairs.push((a.clone(), b.clone()));
+        }
+    }
+
+    let mut solos: Vec<String> = players
+        .iter()
+        .filter(|p| !used.contains(*p))
+        .cloned()
+        .collect();
+    solos.shuffle(&mut rng);
+
+    let mut sitting_out = vec![];
+    if solos.len() % 2 == 1 {
+        sitting_out.push(solos.pop().unwrap());
+    }
+    let mut random_pairs: Vec<(String, String)> = solos
+        .chunks(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect();
+
+    //ペア数が奇数なら1ペア抜ける（固定ペアはなるべく残す）
+    if (fixed_pairs.len() + random_pairs.len()) % 2 == 1 {
+        let (a, b) = random_pairs
+            .pop()
+            .unwrap_or_else(|| fixed_pairs.pop().unwrap());
+        sitting_out.push(a);
+        sitting_out.push(b);
+    }
+
+    let mut pairs = fixed_pairs;
+    pairs.append(&mut random_pairs);
+
+    let mut best: Option<(usize, Vec<Table>)> = None;
+    for _ in 0..SHUFFLE_TRIALS {
+        pairs.shuffle(&mut rng);
+        let tables: Vec<Table> = pairs
+            .chunks(2)
+            .map(|pairs| Table {
+                north: pairs[0].0.clone(),
+                south: pairs[0].1.clone(),
+                east: pairs[1].0.clone(),
+                west: pairs[1].1.clone(),
+            })
+            .collect();
+        let repeats = tables
+            .iter()
+            .flat_map(|table| table.opponents())
+            .filter(|(a, b)| last_opponents.contains(&pair_key(a, b)))
+            .count();
+        if best.as_ref().is_none_or(|(min, _)| repeats < *min) {
+            let done = repeats == 0;
+            best = Some((repeats, tables));
+            if done {
+                break;
+            }
+        }
+    }
+
+    Seating {
+        tables: best.map(|(_, tables)| tables).unwrap_or_default(),
+        sitting_out,
+    }
+}
+
+pub async fn get_seating(attendance_id: &str) -> Option<Seating> {
+    let rows: Vec<(i64, Option<String>, String)> = sqlx::query_as(
+        "select table_no,seat,user_id from seatings where attendance_id = ? order by table_no",
+    )
+    .bind(attendance_id)
+    .fetch_all(DB.get().unwrap())
+    .await
+    .ok()?;
+    if rows.is_empty() {
+        return None;
+    }
+
+    let mut seating = Seating::default();
+    let mut seats: Vec<(i64, Seat, String)> = vec![];
+    for (table_no, seat, user_id) in rows {
+        match seat.as_deref().and_then(Seat::from_code) {
+            Some(seat) => seats.push((table_no, seat, user_id)),
+            None => seating.sitting_out.push(user_id),
+        }
+    }
+    let mut table_no = 0;
+    loop {
+        let find = |seat: Seat| {
+            seats
+                .iter()
+                .find(|(no, s, _)| *no == table_no && *s == seat)
+                .map(|(_, _, user_id)| user_id.clone())
+        };
+        let (Some(north), Some(east), Some(south), Some(west)) = (
+            find(Seat::North),
+            find(Seat::East),
+            find(Seat::South),
+            find(Seat::West),
+        ) else {
+            break;
+        };
+        seating.tables.push(Table {
+            north,
+            east,
+            south,
+            west,
+        });
+        table_no += 1;
+    }
+    Some(seating)
+}
+
+async fn save_seating(attendance_id: &str, seating: &Seating) -> Result<()> {
+    let mut transaction = DB.get().unwrap().begin().await?;
+    sqlx::query("delete from seatings where attendance_id = ?")
+        .bind(attendance_id)
+        .execute(&mut transaction)
+        .await?;
+    for (table_no, table) in seating.tables.iter().enumerate() {
+        for seat in Seat::ALL {
+            sqlx::query(
+                "insert into seatings(attendance_id,table_no,seat,user_id) values(?,?,?,?)",
+            )
+            .bind(attendance_id)
+            .bind(table_no as i64)
+            .bind(seat.as_str())
+            .bind(table.get(seat))
+            .execute(&mut transaction)
+            .await?;
+        }
+    }
+    //抜け番はseatをnullにして保存する
+    for user_id in &seating.sitting_out {
+        sqlx::query("insert into seatings(attendance_id,table_no,user_id) values(?,?,?)")
+            .bind(attendance_id)
+            .bind(-1)
+            .bind(user_id)
+            .execute(&mut transaction)
+            .await?;
+    }
+    transaction.commit().await?;
+    Ok(())
+}
+
+//同じグループで前回卓組みした出欠の対戦相手
+async fn get_last_opponents(attendance_id: &str, group_id: &str) -> HashSet<(String, String)> {
+    let last: Option<String> = sqlx::query_scalar(
+        "select attendance_id from attendances where group_id = ? and attendance_id != ? \
+         and attendance_id in (select attendance_id from seatings) \
+         order by finishing_schedule desc limit 1",
+    )
+    .bind(group_id)
+    .bind(attendance_id)
+    .fetch_optional(DB.get().unwrap())
+    .await
+    .unwrap_or_default();
+    match last {
+        Some(last) => get_seating(&last)
+            .await
+            .map(|seating| seating.opponent_pairs())
+            .unwrap_or_default(),
+        None => HashSet::new(),
+    }
+}
+
+/// 出席者で卓組みをして保存し、グループに送信する
+pub async fn assign_and_post_tables(
+    attendance_id: &str,
+    pairs: &[(String, String)],
+) -> Option<Seating> {
+    let group_id = get_group_id(attendance_id).await?;
+    let attendance = get_attendance_status(attendance_id).await;
+    let last_opponents = get_last_opponents(attendance_id, &group_id).await;
+
+    let seating = assign_tables(&attendance.attend, pairs, &last_opponents);
+    if seating.tables.is_empty() {
+        return None;
+    }
+    save_seating(attendance_id, &seating).await.ok()?;
+
+    let mut text = "卓組み".to_string();
+    for (index, table) in seating.tables.iter().enumerate() {
+        text += &format!("\n{}卓", index + 1);
+        for seat in Seat::ALL {
+            text += &format!(
+                "\n  {}: {}",
+                seat.jp(),
+                get_display_name(table.get(seat), &group_id).await
+            );
+        }
+    }
+    if !seating.sitting_out.is_empty() {
+        let mut names = vec![];
+        for user_id in &seating.sitting_out {
+            names.push(get_display_name(user_id, &group_id).await);
+        }
+        text += &format!("\n抜け: {}", names.join(", "));
+    }
+    let message = PushMessage {
+        to: group_id,
+        messages: vec![Box::new(SimpleMessage::new(&text))],
+    };
+    message.send().await;
+    Some(seating)
+}
+
+#[test]
+fn assign_tables_test() {
+    let players: Vec<String> = (0..10).map(|i| format!("user{i}")).collect();
+    let partnerships = vec![
+        ("user0".to_string(), "user1".to_string()),
+        ("user2".to_string(), "user3".to_string()),
+        ("user4".to_string(), "nobody".to_string()),
+    ];
+    let seating = assign_tables(&players, &partnerships, &HashSet::new());
+
+    assert_eq!(seating.tables.len(), 2);
+    assert_eq!(seating.sitting_out.len(), 2);
+    let mut seated: Vec<&String> = seating
+        .tables
+        .iter()
+        .flat_map(|table| Seat::ALL.map(|seat| table.get(seat)))
+        .chain(seating.sitting_out.iter())
+        .collect();
+    seated.sort();
+    seated.dedup();
+    assert_eq!(seated.len(), players.len());
+
+    for table in &seating.tables {
+        for (a, b) in [("user0", "user1"), ("user2", "user3")] {
+            if table.north == a || table.east == a || table.south == a || table.west == a {
+                let partners = (table.north == a && table.south == b)
+                    || (table.east == a && table.west == b);
+                assert!(partners, "{a} and {b} should be partners");
+            }
+        }
+    }
+}
+
+#[test]
+fn assign_tables_avoid_last_opponents_test() {
+    let players: Vec<String> = (0..8).map(|i| format!("user{i}")).collect();
+    let partnerships: Vec<(String, String)> = players
+        .chunks(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect();
+    let last = Seating {
+        tables: vec![
+            Table {
+                north: "user0".to_string(),
+                south: "user1".to_string(),
+                east: "user2".to_string(),
+                west: "user3".to_string(),
+            },
+            Table {
+                north: "user4".to_string(),
+                south: "user5".to_string(),
+                east: "user6".to_string(),
+                west: "user7".to_string(),
+            },
+        ],
+        sitting_out: vec![],
+    };
+    let seating = assign_tables(&players, &partnerships, &last.opponent_pairs());
+    assert!(seating
+        .opponent_pairs()
+        .is_disjoint(&last.opponent_pairs()));
+}
@@ -0,0 +1,152 @@
+use super::*;
+use axum::extract::ConnectInfo;
+use axum::http::{HeaderMap, Request};
+use axum::middleware::Next;
+use std::time::{Instant, SystemTime};
+use tracing::Instrument;
+
+//証明書が更新されていないか確認する間隔
+const CERTIFICATE_CHECK_SECS: u64 = 60;
+
+fn certificate_paths() -> (PathBuf, PathBuf) {
+    (
+        SETTINGS.TLS_KEY_DIR_PATH.join("fullchain.pem"),
+        SETTINGS.TLS_KEY_DIR_PATH.join("privkey.pem"),
+    )
+}
+
+/// 設定に合わせてHTTPかHTTPSでサーバーを立てる
+pub async fn serve(app: Router, handle: axum_server::Handle) -> std::io::Result<()> {
+    let addr = SocketAddr::from_str(&SETTINGS.LISTENING_ADDRESS).unwrap();
+    let app = app
+        .layer(middleware::from_fn(log_client))
+        .into_make_service_with_connect_info::<SocketAddr>();
+    //nginxなどの後ろで動かすときはTLSを使わない
+    if SETTINGS.PLAIN_HTTP {
+        return axum_server::bind(addr).handle(handle).serve(app).await;
+    }
+
+    let (cert, key) = certificate_paths();
+    let config = RustlsConfig::from_pem_file(&cert, &key).await?;
+    tokio::spawn(watch_certificates(config.clone()));
+    axum_server::bind_rustls(addr, config)
+        .handle(handle)
+        .serve(app)
+        .await
+}
+
+fn last_modified(paths: &[&PathBuf]) -> Option<SystemTime> {
+    paths
+        .iter()
+        .filter_map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .max()
+}
+
+/// 証明書のファイルが更新されたら再起動せずに読み込み直す
+async fn watch_certificates(config: RustlsConfig) {
+    let (cert, key) = certificate_paths();
+    let mut loaded = last_modified(&[&cert, &key]);
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(std::time::Duration::from_secs(CERTIFICATE_CHECK_SECS)) => {}
+            _ = shutdown_requested() => break,
+        }
+        let modified = last_modified(&[&cert, &key]);
+        if modified.is_none() || modified == loaded {
+            continue;
+        }
+        //片方だけ書き換わった途中なら失敗するので、次の確認でもう一度試す
+        match config.reload_from_pem_file(&cert, &key).await {
+            Ok(_) => {
+                tracing::info!("reloaded TLS certificates");
+                loaded = modified;
+            }
+            Err(e) => tracing::warn!(error = %e, "failed to reload TLS certificates"),
+        }
+    }
+}
+
+/// 信頼する設定のときだけ、プロキシが付けたX-Forwarded-*を使う
+fn client_info(
+    headers: &HeaderMap,
+    peer: SocketAddr,
+    default_scheme: &str,
+    trust_forwarded: bool,
+) -> (String, String) {
+    let forwarded = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+    if !trust_forwarded {
+        return (peer.ip().to_string(), default_scheme.to_string());
+    }
+    (
+        forwarded("x-forwarded-for").unwrap_or_else(|| peer.ip().to_string()),
+        forwarded("x-forwarded-proto").unwrap_or_else(|| default_scheme.to_string()),
+    )
+}
+
+fn default_scheme() -> &'static str {
+    if SETTINGS.PLAIN_HTTP {
+        "http"
+    } else {
+        "https"
+    }
+}
+
+/// ブラウザから見たスキーム ("http"か"https")
+pub fn request_scheme(headers: &HeaderMap, peer: SocketAddr) -> String {
+    client_info(headers, peer, default_scheme(), SETTINGS.TRUST_FORWARDED_HEADERS).1
+}
+
+pub async fn log_client<B>(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request<B>,
+    next: Next<B>,
+) -> axum::response::Response {
+    let (client, scheme) = client_info(
+        request.headers(),
+        peer,
+        default_scheme(),
+        SETTINGS.TRUST_FORWARDED_HEADERS,
+    );
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        path = request.uri().path(),
+        client,
+        scheme
+    );
+    async move {
+        let started = Instant::now();
+        let response = next.run(request).await;
+        tracing::info!(
+            status = response.status().as_u16(),
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "handled request"
+        );
+        response
+    }
+    .instrument(span)
+    .await
+}
+
+#[test]
+fn client_info_test() {
+    let peer = SocketAddr::from_str("127.0.0.1:50000").unwrap();
+    let mut headers = HeaderMap::new();
+    headers.insert("x-forwarded-for", "203.0.113.1, 10.0.0.1".parse().unwrap());
+    headers.insert("x-forwarded-proto", "https".parse().unwrap());
+    assert_eq!(
+        client_info(&headers, peer, "http", true),
+        ("203.0.113.1".to_string(), "https".to_string())
+    );
+    assert_eq!(
+        client_info(&headers, peer, "http", false),
+        ("127.0.0.1".to_string(), "http".to_string())
+    );
+}
@@ -0,0 +1,300 @@
+use super::*;
+use std::collections::HashMap;
+use std::fmt;
+
+//設定ファイルの場所はこの環境変数で変えられる
+const SETTINGS_PATH_ENV: &str = "BRIDGE_SETTINGS_PATH";
+const DEFAULT_SETTINGS_PATH: &str = "settings.toml";
+//各項目はこの接頭辞を付けた環境変数で上書きできる (例: BRIDGE_TOKEN)
+const ENV_PREFIX: &str = "BRIDGE_";
+
+#[derive(Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    Json,
+}
+
+#[allow(non_snake_case)]
+#[derive(serde::Deserialize)]
+pub struct Settings {
+    pub TOKEN: String,
+    #[serde(default)]
+    pub TLS_KEY_DIR_PATH: PathBuf,
+    pub HOST: String,
+    #[serde(default = "default_listening_address")]
+    pub LISTENING_ADDRESS: String,
+    pub BINDED_GROUP_ID: String,
+    #[serde(default)]
+    pub DEFAULT_ICON_URL: String,
+    #[serde(default)]
+    pub ADMIN_KEY: Option<String>,
+    #[serde(default)]
+    pub ADMIN_USER_IDS: Vec<String>,
+    #[serde(default)]
+    pub TEMPLATE_DIR: Option<PathBuf>,
+    #[serde(default)]
+    pub RESULT_LINK_SECRET: Option<String>,
+    #[serde(default)]
+    pub GREET_NEW_MEMBERS: bool,
+    #[serde(default)]
+    pub PLAIN_HTTP: bool,
+    #[serde(default)]
+    pub TRUST_FORWARDED_HEADERS: bool,
+    #[serde(default)]
+    pub LOG_FORMAT: LogFormat,
+    #[serde(default)]
+    pub LOG_LEVEL: Option<String>,
+    #[serde(default)]
+    pub EVENT_TEMPLATES: HashMap<String, EventTemplate>,
+}
+
+//トークンなどの秘密はログに出さない
+impl fmt::Debug for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secret = |value: Option<&String>| value.map(|v| redact(v));
+        f.debug_struct("Settings")
+            .field("TOKEN", &redact(&self.TOKEN))
+            .field("TLS_KEY_DIR_PATH", &self.TLS_KEY_DIR_PATH)
+            .field("HOST", &self.HOST)
+            .field("LISTENING_ADDRESS", &self.LISTENING_ADDRESS)
+            .field("BINDED_GROUP_ID", &self.BINDED_GROUP_ID)
+            .field("DEFAULT_ICON_URL", &self.DEFAULT_ICON_URL)
+            .field("ADMIN_KEY", &secret(self.ADMIN_KEY.as_ref()))
+            .field("ADMIN_USER_IDS", &self.ADMIN_USER_IDS)
+            .field("TEMPLATE_DIR", &self.TEMPLATE_DIR)
+            .field("RESULT_LINK_SECRET", &secret(self.RESULT_LINK_SECRET.as_ref()))
+            .field("GREET_NEW_MEMBERS", &self.GREET_NEW_MEMBERS)
+            .field("PLAIN_HTTP", &self.PLAIN_HTTP)
+            .field("TRUST_FORWARDED_HEADERS", &self.TRUST_FORWARDED_HEADERS)
+            .field("LOG_FORMAT", &self.LOG_FORMAT)
+            .field("LOG_LEVEL", &self.LOG_LEVEL)
+            .field("EVENT_TEMPLATES", &self.EVENT_TEMPLATES)
+            .finish()
+    }
+}
+
+fn default_listening_address() -> String {
+    "0.0.0.0:443".to_string()
+}
+
+enum Kind {
+    Text,
+    Flag,
+    List,
+}
+
+//環境変数から上書きできる項目と、その値の読み方
+const FIELDS: &[(&str, Kind)] = &[
+    ("TOKEN", Kind::Text),
+    ("TLS_KEY_DIR_PATH", Kind::Text),
+    ("HOST", Kind::Text),
+    ("LISTENING_ADDRESS", Kind::Text),
+    ("BINDED_GROUP_ID", Kind::Text),
+    ("DEFAULT_ICON_URL", Kind::Text),
+    ("ADMIN_KEY", Kind::Text),
+    ("ADMIN_USER_IDS", Kind::List),
+    ("TEMPLATE_DIR", Kind::Text),
+    ("RESULT_LINK_SECRET", Kind::Text),
+    ("GREET_NEW_MEMBERS", Kind::Flag),
+    ("PLAIN_HTTP", Kind::Flag),
+    ("TRUST_FORWARDED_HEADERS", Kind::Flag),
+    ("LOG_FORMAT", Kind::Text),
+    ("LOG_LEVEL", Kind::Text),
+];
+
+#[derive(Debug)]
+pub enum SettingsError {
+    Read(PathBuf, std::io::Error),
+    Parse(String),
+    Invalid(Vec<String>),
+}
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingsError::Read(path, e) => write!(f, "failed to read {}: {e}", path.display()),
+            SettingsError::Parse(e) => write!(f, "invalid settings: {e}"),
+            SettingsError::Invalid(problems) => {
+                write!(f, "invalid settings:")?;
+                for problem in problems {
+                    write!(f, "\n  - {problem}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+impl std::error::Error for SettingsError {}
+
+impl Settings {
+    /// 設定ファイルと環境変数から読み込む。ファイルが無ければ環境変数だけを使う
+    pub fn load() -> std::result::Result<Self, SettingsError> {
+        let path = PathBuf::from(
+            std::env::var(SETTINGS_PATH_ENV).unwrap_or_else(|_| DEFAULT_SETTINGS_PATH.to_string()),
+        );
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => Some(text),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(SettingsError::Read(path, e)),
+        };
+        Self::from_sources(text.as_deref(), std::env::vars())
+    }
+
+    pub fn from_sources(
+        text: Option<&str>,
+        env: impl Iterator<Item = (String, String)>,
+    ) -> std::result::Result<Self, SettingsError> {
+        let mut table: toml::Table = match text {
+            Some(text) => toml::from_str(text).map_err(|e| SettingsError::Parse(e.to_string()))?,
+            None => toml::Table::new(),
+        };
+        let mut problems = vec![];
+        for (key, value) in env {
+            let Some(name) = key.strip_prefix(ENV_PREFIX) else {continue};
+            let Some((name, kind)) = FIELDS.iter().find(|(field, _)| *field == name) else {continue};
+            let value = match kind {
+                Kind::Text => toml::Value::String(value),
+                Kind::Flag => match value.trim().to_lowercase().as_str() {
+                    "1" | "true" | "yes" => toml::Value::Boolean(true),
+                    "0" | "false" | "no" | "" => toml::Value::Boolean(false),
+                    _ => {
+                        problems.push(format!("{key} must be true or false, got {value:?}"));
+                        continue;
+                    }
+                },
+                //カンマ区切りで複数指定する
+                Kind::List => toml::Value::Array(
+                    value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|v| !v.is_empty())
+                        .map(|v| toml::Value::String(v.to_string()))
+                        .collect(),
+                ),
+            };
+            table.insert(name.to_string(), value);
+        }
+        if !problems.is_empty() {
+            return Err(SettingsError::Invalid(problems));
+        }
+
+        let settings: Settings = toml::Value::Table(table).try_into().map_err(|e: toml::de::Error| {
+            SettingsError::Parse(format!(
+                "{} (set it in the settings file or with the {ENV_PREFIX}<NAME> environment variable)",
+                e.message()
+            ))
+        })?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    fn validate(&self) -> std::result::Result<(), SettingsError> {
+        let mut problems = vec![];
+        for (name, value) in [
+            ("TOKEN", &self.TOKEN),
+            ("HOST", &self.HOST),
+            ("BINDED_GROUP_ID", &self.BINDED_GROUP_ID),
+        ] {
+            if value.trim().is_empty() {
+                problems.push(format!("{name} must not be empty"));
+            }
+        }
+        if SocketAddr::from_str(&self.LISTENING_ADDRESS).is_err() {
+            problems.push(format!(
+                "LISTENING_ADDRESS must be an address like 0.0.0.0:443, got {:?}",
+                self.LISTENING_ADDRESS
+            ));
+        }
+        if let Some(dir) = &self.TEMPLATE_DIR {
+            if !dir.is_dir() {
+                problems.push(format!("TEMPLATE_DIR {} is not a directory", dir.display()));
+            }
+        }
+        for (name, template) in &self.EVENT_TEMPLATES {
+            problems.extend(check_event_template(name, template));
+        }
+        if let Some(level) = &self.LOG_LEVEL {
+            if let Err(e) = tracing_subscriber::EnvFilter::try_new(level) {
+                problems.push(format!("LOG_LEVEL {level:?} is invalid: {e}"));
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(SettingsError::Invalid(problems))
+        }
+    }
+}
+
+impl Settings {
+    /// サーバーを立てるときだけ証明書が必要になる
+    pub fn check_certificates(&self) -> std::result::Result<(), SettingsError> {
+        if self.PLAIN_HTTP {
+            return Ok(());
+        }
+        let problems: Vec<String> = ["fullchain.pem", "privkey.pem"]
+            .iter()
+            .map(|file| self.TLS_KEY_DIR_PATH.join(file))
+            .filter(|path| !path.is_file())
+            .map(|path| {
+                format!(
+                    "{} does not exist (set TLS_KEY_DIR_PATH, or PLAIN_HTTP = true behind a proxy)",
+                    path.display()
+                )
+            })
+            .collect();
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(SettingsError::Invalid(problems))
+        }
+    }
+}
+
+static LOADED: OnceCell<Settings> = OnceCell::new();
+
+/// 起動時に一度だけ呼んで、設定の誤りをまとめて報告する
+pub fn initialize_settings() -> std::result::Result<&'static Settings, SettingsError> {
+    LOADED.get_or_try_init(Settings::load)
+}
+
+pub static SETTINGS: Lazy<&'static Settings> =
+    Lazy::new(|| initialize_settings().unwrap_or_else(|e| panic!("{e}")));
+
+#[test]
+fn settings_env_override_test() {
+    let text = "TOKEN = 'file'\nHOST = 'example.com'\nBINDED_GROUP_ID = 'C1'\nPLAIN_HTTP = true\n";
+    let env = [
+        ("BRIDGE_TOKEN", "from-env"),
+        ("BRIDGE_ADMIN_USER_IDS", "U1, U2"),
+        ("BRIDGE_GREET_NEW_MEMBERS", "true"),
+        ("HOST", "ignored.example.com"),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()));
+    let settings = Settings::from_sources(Some(text), env).unwrap();
+    assert_eq!(settings.TOKEN, "from-env");
+    assert_eq!(settings.HOST, "example.com");
+    assert_eq!(settings.ADMIN_USER_IDS, vec!["U1", "U2"]);
+    assert!(settings.GREET_NEW_MEMBERS);
+    assert_eq!(settings.LISTENING_ADDRESS, "0.0.0.0:443");
+}
+
+#[test]
+fn settings_validation_test() {
+    let missing = Settings::from_sources(Some("HOST = 'example.com'"), std::iter::empty());
+    assert!(missing.unwrap_err().to_string().contains("TOKEN"));
+
+    let text = "TOKEN = ''\nHOST = 'example.com'\nBINDED_GROUP_ID = 'C1'\nLISTENING_ADDRESS = 'nowhere'\n";
+    let message = Settings::from_sources(Some(text), std::iter::empty())
+        .unwrap_err()
+        .to_string();
+    assert!(message.contains("TOKEN must not be empty"));
+    assert!(message.contains("LISTENING_ADDRESS"));
+
+    let text = "TOKEN = 't'\nHOST = 'example.com'\nBINDED_GROUP_ID = 'C1'\n";
+    let settings = Settings::from_sources(Some(text), std::iter::empty()).unwrap();
+    let message = settings.check_certificates().unwrap_err().to_string();
+    assert!(message.contains("fullchain.pem"));
+}
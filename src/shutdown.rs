@@ -0,0 +1,37 @@
+use super::*;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+//処理中のリクエストを待つ最大の時間
+pub const GRACE_PERIOD_SECS: u64 = 10;
+
+static SHUTDOWN: Lazy<watch::Sender<bool>> = Lazy::new(|| watch::channel(false).0);
+
+/// 終了を要求する。何度呼んでもよい
+pub fn request_shutdown() {
+    SHUTDOWN.send_replace(true);
+}
+
+/// 終了が要求されるまで待つ。既に要求されていればすぐに返る
+pub async fn shutdown_requested() {
+    let mut receiver = SHUTDOWN.subscribe();
+    let _ = receiver.wait_for(|requested| *requested).await;
+}
+
+/// SIGINTかSIGTERMを受け取るまで待つ
+pub async fn wait_for_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => tracing::info!("received SIGINT"),
+        _ = terminate.recv() => tracing::info!("received SIGTERM"),
+    }
+}
+
+#[tokio::test]
+async fn shutdown_requested_test() {
+    let waiting = tokio::spawn(shutdown_requested());
+    request_shutdown();
+    waiting.await.unwrap();
+    //要求された後に待ち始めてもすぐに返る
+    shutdown_requested().await;
+}
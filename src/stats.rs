@@ -0,0 +1,152 @@
+use super::*;
+use axum::body::Body;
+use axum::http::Request;
+
+const DEFAULT_EVENT_COUNT: i64 = 20;
+const MAX_EVENT_COUNT: i64 = 200;
+
+#[derive(Debug, Default, PartialEq, serde::Serialize)]
+pub struct MemberStats {
+    pub events: usize,
+    pub attended: usize,
+    pub current_streak: usize,
+    pub longest_streak: usize,
+    pub late_cancellations: usize,
+}
+impl MemberStats {
+    /// 古い順に並んだ各イベントでの最終的な投票から集計する（投票していなければNone）
+    pub fn from_statuses(statuses: &[Option<String>]) -> Self {
+        let mut stats = MemberStats {
+            events: statuses.len(),
+            ..Default::default()
+        };
+        let mut streak = 0;
+        for status in statuses {
+            if status.as_deref() == Some("attend") {
+                stats.attended += 1;
+                streak += 1;
+                stats.longest_streak = stats.longest_streak.max(streak);
+            } else {
+                streak = 0;
+            }
+        }
+        stats.current_streak = streak;
+        stats
+    }
+    pub fn attendance_rate(&self) -> f64 {
+        if self.events == 0 {
+            return 0.0;
+        }
+        self.attended as f64 / self.events as f64
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct CountQuery {
+    n: Option<i64>,
+    token: Option<String>,
+}
+
+fn event_count(query: &CountQuery) -> i64 {
+    query.n.unwrap_or(DEFAULT_EVENT_COUNT).clamp(1, MAX_EVENT_COUNT)
+}
+
+/// 管理者だけが見られる (ルーター側でrequire_adminを掛ける)
+pub async fn history_page(
+    Query(query): Query<CountQuery>,
+) -> std::result::Result<Html<String>, StatusCode> {
+    let attendances: Vec<_> = api::fetch_summaries(None, Some(Utc::now()), event_count(&query), 0)
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .map(|summary| {
+            let token = sign_result_token(&summary.attendance_id, summary.finishing_time);
+            (summary, token)
+        })
+        .collect();
+    render("history.html", minijinja::context! { attendances })
+        .map(Html::from)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub async fn member_page(
+    Path(user_id): Path<String>,
+    Query(query): Query<CountQuery>,
+    request: Request<Body>,
+) -> std::result::Result<Html<String>, StatusCode> {
+    if !is_valid_user_id(&user_id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    //結果ページのリンクから来た人か管理者しか見られない
+    let token_valid = query
+        .token
+        .as_deref()
+        .is_some_and(|token| verify_member_token(&user_id, token));
+    if !token_valid && !is_admin_request(&request) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let attendances: Vec<(String, String)> = sqlx::query_as(
+        "select attendance_id,group_id from attendances where finishing_schedule < ? \
+         order by finishing_schedule desc limit ?",
+    )
+    .bind(Utc::now())
+    .bind(event_count(&query))
+    .fetch_all(DB.get().unwrap())
+    .await
+    .unwrap_or_default();
+
+    let mut statuses = vec![];
+    let mut late_cancellations = 0;
+    for (attendance_id, _) in attendances.iter().rev() {
+        let status: Option<String> = sqlx::query_scalar(&format!(
+            "select status from {attendance_id} where user_id = ?"
+        ))
+        .bind(&user_id)
+        .fetch_optional(DB.get().unwrap())
+        .await
+        .unwrap_or_default();
+        statuses.push(status);
+        late_cancellations += get_late_cancellations(attendance_id)
+            .await
+            .iter()
+            .filter(|record| record.user_id == user_id)
+            .count();
+    }
+    let stats = MemberStats {
+        late_cancellations,
+        ..MemberStats::from_statuses(&statuses)
+    };
+
+    let group_id = attendances
+        .first()
+        .map_or(SETTINGS.BINDED_GROUP_ID.clone(), |(_, group_id)| group_id.clone());
+    let name = get_display_name(&user_id, &group_id).await;
+
+    let rate = format!("{:.0}", stats.attendance_rate() * 100.0);
+    render("member.html", minijinja::context! { name, stats, rate })
+        .map(Html::from)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[test]
+fn member_stats_test() {
+    let statuses: Vec<Option<String>> = [
+        Some("attend"),
+        Some("attend"),
+        None,
+        Some("attend"),
+        Some("attend"),
+        Some("attend"),
+        Some("absent"),
+        Some("attend"),
+    ]
+    .iter()
+    .map(|s| s.map(|s| s.to_string()))
+    .collect();
+    let stats = MemberStats::from_statuses(&statuses);
+    assert_eq!(stats.events, 8);
+    assert_eq!(stats.attended, 6);
+    assert_eq!(stats.longest_streak, 3);
+    assert_eq!(stats.current_streak, 1);
+    assert_eq!(stats.attendance_rate(), 0.75);
+}
@@ -0,0 +1,60 @@
+use super::*;
+use minijinja::Environment;
+
+//テンプレートはバイナリに埋め込んでおく
+const EMBEDDED_TEMPLATES: [(&str, &str); 7] = [
+    ("base.html", include_str!("../templates/base.html")),
+    ("result.html", include_str!("../templates/result.html")),
+    ("history.html", include_str!("../templates/history.html")),
+    ("member.html", include_str!("../templates/member.html")),
+    ("admin.html", include_str!("../templates/admin.html")),
+    ("admin_login.html", include_str!("../templates/admin_login.html")),
+    ("admin_error.html", include_str!("../templates/admin_error.html")),
+];
+
+static EMBEDDED_ENV: Lazy<Environment<'static>> = Lazy::new(|| {
+    let mut env = Environment::new();
+    for (name, source) in EMBEDDED_TEMPLATES {
+        env.add_template(name, source).unwrap();
+    }
+    env
+});
+
+/// テンプレートを描画する。.htmlのテンプレートでは値は自動でエスケープされる。
+/// TEMPLATE_DIRが設定されていれば、毎回そこから読み直すので再起動せずに編集できる
+pub fn render<S: serde::Serialize>(name: &str, context: S) -> Result<String> {
+    match &SETTINGS.TEMPLATE_DIR {
+        Some(dir) => {
+            let mut env = Environment::new();
+            env.set_loader(minijinja::path_loader(dir));
+            Ok(env.get_template(name)?.render(context)?)
+        }
+        None => Ok(EMBEDDED_ENV.get_template(name)?.render(context)?),
+    }
+}
+
+#[test]
+fn escape_test() {
+    let member = minijinja::context! {
+        user_id => "U1",
+        name => "<script>alert(1)</script>",
+        icon => "https://example.com/icon.png",
+    };
+    let html = EMBEDDED_ENV
+        .get_template("result.html")
+        .unwrap()
+        .render(minijinja::context! {
+            title => "2/20(月)<b>四谷練</b>",
+            attends => vec![member],
+            holdings => Vec::<()>::new(),
+            absents => Vec::<()>::new(),
+            waitings => Vec::<()>::new(),
+            seating => Vec::<()>::new(),
+            history => Vec::<()>::new(),
+        })
+        .unwrap();
+    assert!(!html.contains("<script>"));
+    assert!(html.contains("&lt;script&gt;"));
+    assert!(!html.contains("<b>"));
+    assert!(html.contains("参加 1人"));
+}